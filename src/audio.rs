@@ -0,0 +1,236 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rodio::{DeviceTrait, Sink, Source};
+
+use crate::buzzer::{Buzzer, BuzzerConfig};
+use crate::chip8::AudioPattern;
+
+pub const AUDIO_BACKENDS: [&str; 3] = ["rodio", "null", "wav"];
+
+const SAMPLE_RATE: u32 = 44_100;
+
+// Output for the sound of the core, updated after every instruction and timer tick
+pub trait AudioSink {
+    fn update(&mut self, playing: bool, pattern: Option<AudioPattern>);
+
+    // Emulated time elapsed since the last call, for outputs that do not follow the wall clock
+    fn advance(&mut self, _seconds: f64) {}
+
+    fn finish(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+}
+
+// Plays the buzzer on the default output device, the stream never pauses so the ramps are heard
+pub struct RodioSink {
+    // Kept alive for the stream to keep playing
+    _sink: Sink,
+    state: Arc<SoundState>,
+    // Last pattern sent to the stream, the lock is only taken when it changes
+    pattern: Option<AudioPattern>,
+}
+
+/*
+ * Shared with the audio thread, read for every sample without locking
+ * The pattern is only locked when its version changed.
+ */
+struct SoundState {
+    playing: AtomicBool,
+    pattern_version: AtomicU64,
+    pattern: Mutex<Option<AudioPattern>>,
+}
+
+impl RodioSink {
+    // None without a usable output device, e.g. ALSA without a sound card
+    pub fn new(config: BuzzerConfig) -> Option<Self> {
+        let device = rodio::default_output_device()?;
+        // Sink::new panics on devices without a name or an output format
+        device.name().ok()?;
+        device.default_output_format().ok()?;
+        let sink = Sink::new(&device);
+        let state = Arc::new(SoundState {
+            playing: AtomicBool::new(false),
+            pattern_version: AtomicU64::new(0),
+            pattern: Mutex::new(None),
+        });
+        sink.append(BuzzerSource {
+            buzzer: Buzzer::new(config, SAMPLE_RATE),
+            state: state.clone(),
+            pattern_version: 0,
+        });
+        Some(RodioSink { _sink: sink, state, pattern: None })
+    }
+}
+
+impl AudioSink for RodioSink {
+    fn update(&mut self, playing: bool, pattern: Option<AudioPattern>) {
+        if pattern != self.pattern {
+            if let Ok(mut shared) = self.state.pattern.lock() {
+                *shared = pattern;
+                self.pattern = pattern;
+                self.state.pattern_version.fetch_add(1, Ordering::Release);
+            }
+        }
+        self.state.playing.store(playing, Ordering::Relaxed);
+    }
+}
+
+struct BuzzerSource {
+    buzzer: Buzzer,
+    state: Arc<SoundState>,
+    // Version of the pattern given to the buzzer
+    pattern_version: u64,
+}
+
+impl Iterator for BuzzerSource {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let version = self.state.pattern_version.load(Ordering::Acquire);
+        if version != self.pattern_version {
+            if let Ok(pattern) = self.state.pattern.lock() {
+                self.buzzer.set_pattern(*pattern);
+                self.pattern_version = version;
+            }
+        }
+        self.buzzer.set_gate(self.state.playing.load(Ordering::Relaxed));
+        Some(self.buzzer.next_sample())
+    }
+}
+
+impl Source for BuzzerSource {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+// Discards the sound, e.g. on machines without sound hardware
+pub struct NullSink;
+
+impl AudioSink for NullSink {
+    fn update(&mut self, _playing: bool, _pattern: Option<AudioPattern>) {}
+}
+
+// Writes the buzzer to a 16 bits mono WAV file, following the emulated time
+pub struct WavSink {
+    writer: Option<hound::WavWriter<BufWriter<File>>>,
+    buzzer: Buzzer,
+    // Emulated seconds since the start, and samples written so far
+    time: f64,
+    written_samples: u64,
+}
+
+impl WavSink {
+    pub fn create(path: &Path, config: BuzzerConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let writer = hound::WavWriter::create(path, spec)?;
+        Ok(WavSink {
+            writer: Some(writer),
+            buzzer: Buzzer::new(config, SAMPLE_RATE),
+            time: 0.0,
+            written_samples: 0,
+        })
+    }
+}
+
+impl AudioSink for WavSink {
+    fn update(&mut self, playing: bool, pattern: Option<AudioPattern>) {
+        self.buzzer.set_gate(playing);
+        self.buzzer.set_pattern(pattern);
+    }
+
+    fn advance(&mut self, seconds: f64) {
+        let writer = match &mut self.writer {
+            Some(writer) => writer,
+            None => return,
+        };
+        self.time += seconds;
+        let total_samples = (self.time * SAMPLE_RATE as f64).round() as u64;
+        while self.written_samples < total_samples {
+            self.written_samples += 1;
+            let sample = self.buzzer.next_sample();
+            if let Err(e) = writer.write_sample((sample * i16::MAX as f32) as i16) {
+                eprintln!("Stopped writing audio: {}", e);
+                self.writer = None;
+                return;
+            }
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(writer) = self.writer.take() {
+            writer.finalize()?;
+        }
+        Ok(())
+    }
+}
+
+// Sends the sound to several outputs, e.g. the sound device and a WAV export
+pub struct AudioOutputs {
+    sinks: Vec<Box<dyn AudioSink>>,
+}
+
+impl AudioOutputs {
+    pub fn new(sinks: Vec<Box<dyn AudioSink>>) -> Self {
+        AudioOutputs { sinks }
+    }
+}
+
+impl AudioSink for AudioOutputs {
+    fn update(&mut self, playing: bool, pattern: Option<AudioPattern>) {
+        for sink in &mut self.sinks {
+            sink.update(playing, pattern);
+        }
+    }
+
+    fn advance(&mut self, seconds: f64) {
+        for sink in &mut self.sinks {
+            sink.advance(seconds);
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        for sink in &mut self.sinks {
+            sink.finish()?;
+        }
+        Ok(())
+    }
+}
+
+// Falls back to no sound, with a warning, when there is no output device
+pub fn create_sink(backend: &str, wav_path: &Path, config: BuzzerConfig) -> Result<Box<dyn AudioSink>, Box<dyn std::error::Error>> {
+    match backend {
+        "rodio" => match RodioSink::new(config) {
+            Some(sink) => Ok(Box::new(sink)),
+            None => {
+                eprintln!("Warning: no audio output device found, sound is disabled");
+                Ok(Box::new(NullSink))
+            }
+        },
+        "null" => Ok(Box::new(NullSink)),
+        "wav" => Ok(Box::new(WavSink::create(wav_path, config)?)),
+        _ => Err(format!("invalid audio backend {}", backend).into())
+    }
+}
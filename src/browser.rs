@@ -0,0 +1,210 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use minifb::{Key, KeyRepeat, Window, WindowOptions};
+
+use crate::audio::NullSink;
+use crate::chip8::{Chip8, CHIP_FREQUENCY, DISPLAY_HEIGHT, DISPLAY_SIZE, DISPLAY_WIDTH};
+use crate::config::parse_values;
+use crate::loader::{ROM_EXTENSIONS, load_rom};
+use crate::overlay::{Canvas, text_height};
+use crate::palette::Palette;
+use crate::scheduler::{Scheduler, TimingMode};
+
+// Next to the ROMs, each line is `file name = title`
+const TITLES_FILE: &str = "titles.txt";
+// In the home directory, one ROM path per line, most recent first
+const RECENT_FILE: &str = ".chip8-recent";
+const MAX_RECENT: usize = 5;
+
+// Emulated time before the preview is taken, long enough for most title screens
+const PREVIEW_SECONDS: f64 = 2.0;
+const PREVIEW_SEED: u64 = 0;
+const MARGIN: usize = 2;
+const PAGE_SIZE: usize = 10;
+
+// Recently played ROMs, shown first in the browser
+pub struct RecentRoms {
+    path: Option<PathBuf>,
+    roms: Vec<PathBuf>,
+}
+
+impl RecentRoms {
+    pub fn load() -> Self {
+        let path = env::var_os("HOME").map(|home| Path::new(&home).join(RECENT_FILE));
+        let roms = path.as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|content| content.lines().filter(|line| !line.is_empty()).map(PathBuf::from).collect())
+            .unwrap_or_default();
+        RecentRoms { path, roms }
+    }
+
+    pub fn roms(&self) -> &[PathBuf] {
+        &self.roms
+    }
+
+    pub fn add(&mut self, rom: &Path) {
+        let rom = rom.canonicalize().unwrap_or_else(|_| rom.to_path_buf());
+        self.roms.retain(|path| *path != rom);
+        self.roms.insert(0, rom);
+        self.roms.truncate(MAX_RECENT);
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        match &self.path {
+            Some(path) => {
+                let lines: Vec<String> = self.roms.iter().map(|rom| rom.display().to_string()).collect();
+                fs::write(path, lines.join("\n") + "\n")
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+struct Entry {
+    path: PathBuf,
+    title: String,
+    recent: bool,
+}
+
+/*
+ * Lists the recent ROMs, then the ROMs of `dir`, with a preview of the selected one
+ * Up / Down           => select a ROM
+ * Page Up / Page Down => select a ROM a page away
+ * Enter               => play the selected ROM
+ * Escape              => quit
+ * Returns the selected ROM, or None to quit.
+ */
+pub fn browse(dir: &Path, recent: &[PathBuf], scale: usize, font_pixel_size: usize, palette: &Palette) -> Result<Option<PathBuf>, Box<dyn std::error::Error>> {
+    let entries = list_roms(dir, recent);
+    let mut previews: HashMap<PathBuf, Option<[u8; DISPLAY_SIZE]>> = HashMap::new();
+    let mut selected = 0;
+
+    let width = DISPLAY_WIDTH * scale;
+    let height = DISPLAY_HEIGHT * scale;
+    let mut buffer: Vec<u32> = vec![0; width * height];
+    let mut window = Window::new(
+        "chip8 - ROMs",
+        width,
+        height,
+        WindowOptions::default(), )?;
+    window.limit_update_rate(Some(std::time::Duration::from_micros(16600)));
+
+    while window.is_open() {
+        for key in window.get_keys_pressed(KeyRepeat::Yes) {
+            match key {
+                Key::Escape => return Ok(None),
+                Key::Enter if !entries.is_empty() => return Ok(Some(entries[selected].path.clone())),
+                Key::Up => selected = selected.saturating_sub(1),
+                Key::Down => selected = (selected + 1).min(entries.len().saturating_sub(1)),
+                Key::PageUp => selected = selected.saturating_sub(PAGE_SIZE),
+                Key::PageDown => selected = (selected + PAGE_SIZE).min(entries.len().saturating_sub(1)),
+                _ => {}
+            }
+        }
+
+        buffer.iter_mut().for_each(|pixel| *pixel = palette.color(0));
+        if let Some(entry) = entries.get(selected) {
+            let preview = previews.entry(entry.path.clone()).or_insert_with(|| preview(&entry.path));
+            if let Some(display) = preview {
+                draw_preview(&mut buffer, width, display, scale / 2, palette);
+            }
+        }
+
+        let mut canvas = Canvas::new(&mut buffer, width, height, font_pixel_size);
+        let lines = visible_lines(&entries, dir, selected, canvas.height_in_font_pixels());
+        let lines: Vec<&str> = lines.iter().map(|line| line.as_str()).collect();
+        let highlighted = lines.iter().position(|line| line.starts_with('>'));
+        canvas.draw_lines(&lines, MARGIN, MARGIN, highlighted);
+
+        window.update_with_buffer(&buffer, width, height)?;
+    }
+    Ok(None)
+}
+
+// Recent ROMs that still exist, then the ROMs of `dir` by file name
+fn list_roms(dir: &Path, recent: &[PathBuf]) -> Vec<Entry> {
+    let mut titles: HashMap<PathBuf, HashMap<String, String>> = HashMap::new();
+    let mut title = |path: &Path| {
+        let dir = path.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+        let name = path.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+        titles.entry(dir.clone()).or_insert_with(|| load_titles(&dir))
+            .get(&name).cloned()
+            .unwrap_or(name)
+    };
+
+    let mut entries: Vec<Entry> = recent.iter()
+        .filter(|path| path.is_file())
+        .map(|path| Entry { path: path.clone(), title: title(path), recent: true })
+        .collect();
+
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .map(|entries| entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect())
+        .unwrap_or_default();
+    paths.retain(|path| is_rom(path));
+    paths.sort();
+    for path in paths {
+        entries.push(Entry { title: title(&path), path, recent: false });
+    }
+    entries
+}
+
+// Files with a ROM extension, or without extension like the ROMs in rom/
+fn is_rom(path: &Path) -> bool {
+    path.is_file() && match path.extension() {
+        Some(extension) => ROM_EXTENSIONS.iter().any(|rom_extension| extension.eq_ignore_ascii_case(rom_extension)),
+        None => true,
+    }
+}
+
+fn load_titles(dir: &Path) -> HashMap<String, String> {
+    let path = dir.join(TITLES_FILE);
+    fs::read_to_string(&path).ok()
+        .and_then(|content| parse_values(&content, &path.display().to_string()).map_err(|e| eprintln!("{}", e)).ok())
+        .unwrap_or_default()
+}
+
+// Display after a short headless run, as far as it got if the ROM fails, None if it cannot be loaded
+fn preview(path: &Path) -> Option<[u8; DISPLAY_SIZE]> {
+    let rom = load_rom(path).ok()?;
+    let mut chip = Chip8::new(rom.program).ok()?;
+    chip.set_seed(PREVIEW_SEED);
+    let mut scheduler = Scheduler::new(CHIP_FREQUENCY, TimingMode::Fixed);
+    let _ = scheduler.run(&mut chip, PREVIEW_SECONDS, &mut NullSink);
+    Some(chip.get_display())
+}
+
+// In the top right corner, `scale` pixels per emulated pixel, clipped to the buffer
+fn draw_preview(buffer: &mut [u32], width: usize, display: &[u8; DISPLAY_SIZE], scale: usize, palette: &Palette) {
+    let scale = scale.max(1);
+    let height = buffer.len() / width;
+    let x = width.saturating_sub(DISPLAY_WIDTH * scale + scale);
+    let y = scale;
+    for j in 0..(DISPLAY_HEIGHT * scale).min(height.saturating_sub(y)) {
+        for i in 0..(DISPLAY_WIDTH * scale).min(width - x) {
+            let pixel = display[i / scale + (j / scale) * DISPLAY_WIDTH];
+            buffer[x + i + (y + j) * width] = palette.color(pixel);
+        }
+    }
+}
+
+// Heading, then the entries around the selected one that fit in `height` font pixels
+fn visible_lines(entries: &[Entry], dir: &Path, selected: usize, height: usize) -> Vec<String> {
+    let mut lines = vec!["ROMS - ENTER TO PLAY, ESC TO QUIT".to_string(), String::new()];
+    if entries.is_empty() {
+        lines.push(format!("No ROM in {}", dir.display()));
+        return lines;
+    }
+
+    let rows = (height.saturating_sub(2 * MARGIN) / text_height(1)).saturating_sub(lines.len()).max(1);
+    let first = selected.saturating_sub(rows / 2).min(entries.len().saturating_sub(rows));
+    for (i, entry) in entries.iter().enumerate().skip(first).take(rows) {
+        let cursor = if i == selected { '>' } else { ' ' };
+        let recent = if entry.recent { " *" } else { "" };
+        lines.push(format!("{} {}{}", cursor, entry.title, recent));
+    }
+    lines
+}
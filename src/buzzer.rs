@@ -0,0 +1,94 @@
+use crate::chip8::{AudioPattern, AUDIO_PATTERN_SIZE};
+
+const PATTERN_BITS: f32 = (AUDIO_PATTERN_SIZE * 8) as f32;
+
+pub const DEFAULT_TONE_FREQUENCY: &str = "440";
+pub const DEFAULT_VOLUME: &str = "0.25";
+pub const DEFAULT_RAMP_MILLISECONDS: &str = "5";
+
+#[derive(Debug, Clone, Copy)]
+pub struct BuzzerConfig {
+    // Hz
+    pub frequency: f32,
+    // 0.0 to 1.0
+    pub volume: f32,
+    // Seconds to go from silence to full volume, and back
+    pub attack: f32,
+    pub release: f32,
+}
+
+/*
+ * Square wave generator, one sample at a time.
+ * The gate follows the sound timer, the attack and release ramps avoid pops when it changes.
+ * When the ROM loaded an XO-CHIP audio pattern, its bits are played instead of the square wave.
+ */
+pub struct Buzzer {
+    config: BuzzerConfig,
+    sample_rate: u32,
+    gate: bool,
+    pattern: Option<AudioPattern>,
+    // Position in the current period, 0.0 to 1.0, or in the pattern, 0.0 to 128.0
+    phase: f32,
+    // Envelope, 0.0 to 1.0
+    gain: f32,
+}
+
+impl Buzzer {
+    pub fn new(config: BuzzerConfig, sample_rate: u32) -> Self {
+        Buzzer {
+            config,
+            sample_rate,
+            gate: false,
+            pattern: None,
+            phase: 0.0,
+            gain: 0.0,
+        }
+    }
+
+    pub fn set_gate(&mut self, gate: bool) {
+        self.gate = gate;
+    }
+
+    pub fn set_pattern(&mut self, pattern: Option<AudioPattern>) {
+        self.pattern = pattern;
+    }
+
+    pub fn next_sample(&mut self) -> f32 {
+        let (target, ramp) = if self.gate {
+            (1.0, self.config.attack)
+        } else {
+            (0.0, self.config.release)
+        };
+        let step = if ramp > 0.0 {
+            1.0 / (ramp * self.sample_rate as f32)
+        } else {
+            1.0
+        };
+        self.gain = if self.gain < target {
+            (self.gain + step).min(target)
+        } else {
+            (self.gain - step).max(target)
+        };
+
+        if self.gain == 0.0 {
+            // Restart the wave on the next beep
+            self.phase = 0.0;
+            return 0.0;
+        }
+
+        let high = match &self.pattern {
+            Some(pattern) => {
+                let high = pattern.bit(self.phase as usize);
+                self.phase = (self.phase + pattern.playback_rate() / self.sample_rate as f32) % PATTERN_BITS;
+                high
+            }
+            None => {
+                let high = self.phase < 0.5;
+                self.phase = (self.phase + self.config.frequency / self.sample_rate as f32).fract();
+                high
+            }
+        };
+        let level = if high { 1.0 } else { -1.0 };
+        level * self.config.volume * self.gain
+    }
+}
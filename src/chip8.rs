@@ -1,46 +1,119 @@
 use rand::prelude::*;
+use rand::rngs::StdRng;
+use std::error::Error;
+use std::fmt;
+
+use crate::font::{Font, BIG_CHARACTER_SIZE, FONT_SIZE, SMALL_CHARACTER_SIZE, SMALL_FONT_SIZE};
 
 pub const CHIP_FREQUENCY: f64 = 500.0;
 
 const NUMBER_OF_REGISTER: usize = 16;
 
-const MEMORY_SIZE: usize = 0xFFF;
-const START_PROGRAM_SPACE: usize = 0x200;
+pub const MEMORY_SIZE: usize = 0x1000;
+pub const START_PROGRAM_SPACE: usize = 0x200;
 
-const STACK_SIZE: usize = 16;
+/*
+ * Errors that stop the core, the address is the one of the faulting instruction
+ * They can be told apart with `error.downcast_ref::<Fault>()`.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    InvalidOpcode { address: u16, opcode: u16 },
+    // Access past 0xFFF, with MemoryOverflow::Error
+    MemoryOutOfBounds { address: u16, access: usize },
+    // Call with `depth` return addresses already on the stack
+    StackOverflow { address: u16, depth: usize },
+    // Return with an empty stack
+    StackUnderflow { address: u16 },
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::InvalidOpcode { address, opcode } => write!(f, "invalid opcode {:X} at 0x{:03X}", opcode, address),
+            Fault::MemoryOutOfBounds { address, access } => write!(f, "memory access out of bounds at 0x{:X}, by 0x{:03X}", access, address),
+            Fault::StackOverflow { address, depth } => write!(f, "stack overflow at 0x{:03X}, more than {} nested calls", address, depth),
+            Fault::StackUnderflow { address } => write!(f, "stack underflow at 0x{:03X}, return without call", address),
+        }
+    }
+}
+
+impl Error for Fault {}
+
+pub const MEMORY_OVERFLOW_MODES: [&str; 2] = ["wrap", "error"];
+
+/*
+ * Access past the end of memory, e.g. I + Vx beyond 0xFFF
+ * wrap  => back to 0x000, like the 12-bit address bus of most interpreters
+ * error => stop the emulation with an error
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MemoryOverflow {
+    Wrap,
+    Error,
+}
+
+impl MemoryOverflow {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "wrap" => Ok(MemoryOverflow::Wrap),
+            "error" => Ok(MemoryOverflow::Error),
+            _ => Err(format!("invalid memory overflow mode {}", value))
+        }
+    }
+}
+
+pub const VIP_STACK_DEPTH: usize = 12;
+pub const SCHIP_STACK_DEPTH: usize = 16;
+pub const STACK_DEPTHS: [(&str, usize); 2] = [("vip", VIP_STACK_DEPTH), ("schip", SCHIP_STACK_DEPTH)];
+
+pub const AUDIO_PATTERN_SIZE: usize = 16;
+const DEFAULT_PITCH: u8 = 64;
+const PATTERN_BASE_RATE: f32 = 4000.0;
+const PITCH_STEPS_PER_OCTAVE: f32 = 48.0;
 
 pub const NUMBER_OF_KEYS: usize = 16;
-pub const KEY_PRESSED: u8 = 0xFF;
-pub const KEY_NOT_PRESSED: u8 = 0x00;
+const KEY_PRESSED: u8 = 0xFF;
+const KEY_NOT_PRESSED: u8 = 0x00;
+
+pub const KEY_WAIT_MODES: [&str; 2] = ["release", "press"];
+
+/*
+ * When Fx0A completes
+ * release => once the key is pressed then released, like the COSMAC VIP
+ * press   => as soon as a key is held, so a held key completes every Fx0A
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyWaitMode {
+    Release,
+    Press,
+}
+
+impl KeyWaitMode {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "release" => Ok(KeyWaitMode::Release),
+            "press" => Ok(KeyWaitMode::Press),
+            _ => Err(format!("invalid key wait mode {}", value))
+        }
+    }
+}
+
+// Keys that went down and up, bit k for key k
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+struct KeyEdges {
+    pressed: u16,
+    released: u16,
+}
 
 pub const DISPLAY_WIDTH: usize = 64;
 pub const DISPLAY_HEIGHT: usize = 32;
 pub const DISPLAY_SIZE: usize = DISPLAY_WIDTH * DISPLAY_HEIGHT;
-pub const PIXEL_ON: u32 = 0xFFFF_FFFF;
-pub const PIXEL_OFF: u32 = 0;
-
-const FONT_SET_ADDRESS_START: usize = 0x050;
-const NUMBER_OF_CHARACTERS: usize = 16;
-const NUMBER_OF_BYTES_PER_CHARACTER: usize = 5;
-const FONT_SET_SIZE: usize = NUMBER_OF_CHARACTERS * NUMBER_OF_BYTES_PER_CHARACTER;
-const FONT_SET: [u8; FONT_SET_SIZE] = [
-    0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
-    0x20, 0x60, 0x20, 0x20, 0x70, // 1
-    0xF0, 0x10, 0xF0, 0x80, 0xF0, // 2
-    0xF0, 0x10, 0xF0, 0x10, 0xF0, // 3
-    0x90, 0x90, 0xF0, 0x10, 0x10, // 4
-    0xF0, 0x80, 0xF0, 0x10, 0xF0, // 5
-    0xF0, 0x80, 0xF0, 0x90, 0xF0, // 6
-    0xF0, 0x10, 0x20, 0x40, 0x40, // 7
-    0xF0, 0x90, 0xF0, 0x90, 0xF0, // 8
-    0xF0, 0x90, 0xF0, 0x10, 0xF0, // 9
-    0xF0, 0x90, 0xF0, 0x90, 0x90, // A
-    0xE0, 0x90, 0xE0, 0x90, 0xE0, // B
-    0xF0, 0x80, 0x80, 0x80, 0xF0, // C
-    0xE0, 0x90, 0x90, 0x90, 0xE0, // D
-    0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
-    0xF0, 0x80, 0xF0, 0x80, 0x80  // F
-];
+// Logical pixel values, mapped to colors by the frontend palette
+pub const PIXEL_ON: u8 = 1;
+pub const PIXEL_OFF: u8 = 0;
+
+pub const FONT_SET_ADDRESS_START: usize = 0x050;
 
 /*
  * nnn => lowest 12 bits of instruction
@@ -49,11 +122,11 @@ const FONT_SET: [u8; FONT_SET_SIZE] = [
  * y   => upper 4 bits of low byte of instruction
  * kk  => lowest byte of instruction
  */
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 enum Instruction {
     // 00E0 Clear display
     Clear,
-    // 00EE Return from subroutine, set PC=top stack, sp -= 1
+    // 00EE Return from subroutine, set PC=top stack and pop it
     Return,
     // 1nnn Jump to location nnn
     Jump(u16),
@@ -121,10 +194,16 @@ enum Instruction {
     SetTimer(usize),
     // Fx18 Set sound timer = Vx.
     SetSoundTimer(usize),
+    // F002 XO-CHIP: Load the 16 bytes audio pattern from memory starting at location I.
+    LoadAudioPattern,
+    // Fx3A XO-CHIP: Set the audio pattern pitch = Vx.
+    SetPitch(usize),
     // Fx1E Set I = I + Vx.
     AddIndex(usize),
     // Fx29 Set I = location of sprite for digit Vx.
     LoadSpriteLocationIndex(usize),
+    // Fx30 SUPER-CHIP: Set I = location of the 8x10 sprite for digit Vx.
+    LoadBigSpriteLocationIndex(usize),
     // Fx33 Store BCD representation of Vx in memory locations I, I+1, and I+2.
     BinaryCodedDecimal(usize),
     // Fx55 Store registers V0 through Vx in memory starting at location I.
@@ -133,26 +212,126 @@ enum Instruction {
     ReadRegisters(usize),
 }
 
+/*
+ * Approximate cost of each instruction in the COSMAC VIP interpreter, in 1802 machine cycles
+ * (8 clock cycles at 1.7609 MHz): the fetch and decode loop of the interpreter, then the handler.
+ * Dxyn also costs cycles per sprite row, more when the sprite is not aligned on a byte,
+ * and waits for the next vertical blank.
+ * The display DMA and the interrupt routine are charged per frame by the scheduler.
+ */
+const VIP_FETCH_DECODE_CYCLES: u32 = 68;
+const VIP_SPRITE_ROW_CYCLES: u32 = 10;
+const VIP_UNALIGNED_SPRITE_ROW_CYCLES: u32 = 8;
+
+impl Instruction {
+    fn vip_machine_cycles(&self) -> u32 {
+        VIP_FETCH_DECODE_CYCLES + self.vip_handler_cycles()
+    }
+
+    // Cycles of the handler alone, after the fetch and decode
+    fn vip_handler_cycles(&self) -> u32 {
+        match self {
+            Instruction::Clear => 24,
+            Instruction::Return => 23,
+            Instruction::Jump(_) => 23,
+            Instruction::Call(_) => 23,
+            Instruction::SkipNextIfEqualByte(_, _) => 12,
+            Instruction::SkipNextIfNotEqualByte(_, _) => 12,
+            Instruction::SkipNextIfEqualRegister(_, _) => 16,
+            Instruction::LoadByte(_, _) => 6,
+            Instruction::AddByte(_, _) => 10,
+            Instruction::LoadRegister(_, _) => 44,
+            Instruction::Or(_, _) => 44,
+            Instruction::And(_, _) => 44,
+            Instruction::Xor(_, _) => 44,
+            Instruction::AddRegister(_, _) => 44,
+            Instruction::Sub(_, _) => 44,
+            Instruction::ShiftRight(_) => 44,
+            Instruction::SubFrom(_, _) => 44,
+            Instruction::ShiftLeft(_) => 44,
+            Instruction::SkipNextIfNotEqualRegister(_, _) => 16,
+            Instruction::SetIndex(_) => 12,
+            Instruction::JumpOf(_) => 23,
+            Instruction::Random(_, _) => 36,
+            Instruction::DisplaySprite(_, _, _) => 26,
+            Instruction::SkipIfKeyPressed(_) => 16,
+            Instruction::SkipIfNotKeyPressed(_) => 16,
+            Instruction::LoadTimer(_) => 10,
+            Instruction::WaitKeyPress(_) => 10,
+            Instruction::SetTimer(_) => 10,
+            Instruction::SetSoundTimer(_) => 10,
+            Instruction::LoadAudioPattern => 10,
+            Instruction::SetPitch(_) => 10,
+            Instruction::AddIndex(_) => 19,
+            Instruction::LoadSpriteLocationIndex(_) => 20,
+            // Not on the VIP, same as Fx29
+            Instruction::LoadBigSpriteLocationIndex(_) => 20,
+            Instruction::BinaryCodedDecimal(_) => 204,
+            Instruction::StoreRegisters(x) => 14 + 14 * (*x as u32 + 1),
+            Instruction::ReadRegisters(x) => 14 + 14 * (*x as u32 + 1),
+        }
+    }
+}
+
+// Time taken by an executed instruction, used by the VIP timing mode
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct InstructionCost {
+    pub machine_cycles: u32,
+    pub wait_for_vblank: bool,
+}
+
+/*
+ * XO-CHIP sound: 128 1-bit samples, played in a loop while the sound timer is non-zero
+ * The playback rate is 4000 * 2 ^ ((pitch - 64) / 48) bits per second
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AudioPattern {
+    pub buffer: [u8; AUDIO_PATTERN_SIZE],
+    pub pitch: u8,
+}
+
+impl AudioPattern {
+    pub fn playback_rate(&self) -> f32 {
+        PATTERN_BASE_RATE * 2f32.powf((self.pitch as f32 - DEFAULT_PITCH as f32) / PITCH_STEPS_PER_OCTAVE)
+    }
+
+    pub fn bit(&self, position: usize) -> bool {
+        let position = position % (AUDIO_PATTERN_SIZE * 8);
+        self.buffer[position / 8] & (0x80 >> (position % 8)) > 0
+    }
+}
+
 pub struct Chip8 {
     // Registers
     registers: [u8; NUMBER_OF_REGISTER],
     // 0x000 - 0x1FF reserved for interpreter
-    // | 0x50-0xA0 16 characters 0 to F
-    // 0x200 - 0xFFF Program / Data Space
+    // | 0x050-0x09F 16 characters 0 to F, then 0x0A0-0x13F the big characters, by default
+    // 0x200 - 0xFFF Program / Data Space, from 0x600 on the ETI 660
     memory: [u8; MEMORY_SIZE],
+    // Decoded instruction at each address, cleared when one of its two bytes is written
+    decoded: Vec<Option<Instruction>>,
+    cache_instructions: bool,
+    program_start: usize,
+    overflow: MemoryOverflow,
+    // Small then big font, at 0x050 by default
+    font: Font,
+    font_address: usize,
     // Index register
     index: u16,
     // Program counter
     program_counter: u16,
-    // Stack
-    stack: [u16; STACK_SIZE],
-    // Index of the top of the stack
-    stack_pointer: usize,
+    // Return addresses, the top of the stack last
+    stack: Vec<u16>,
+    // Nested calls before a stack overflow, 12 on the VIP, 16 on the SUPER-CHIP
+    stack_depth: usize,
     // Decrease (if non-zero) at rate of 60 Hz
     delay_timer: u8,
     // Decrease (if non-zero) at rate of 60 Hz
     // Play sound while non-zero
     sound_timer: u8,
+    // None until a ROM loads a pattern, the frontend then plays its own tone
+    audio_pattern: Option<[u8; AUDIO_PATTERN_SIZE]>,
+    pitch: u8,
     /*
      * Keypad
      * 1 2 3 C
@@ -166,34 +345,130 @@ pub struct Chip8 {
      * z x c v
      */
     keypad: [u8; NUMBER_OF_KEYS],
-    display: [u32; DISPLAY_SIZE],
+    // Edges since the previous instruction, or during Fx0A
+    key_edges: KeyEdges,
+    key_wait: KeyWaitMode,
+    // Key pressed during Fx0A, completed when it is released
+    waiting_release: Option<usize>,
+    display: [u8; DISPLAY_SIZE],
+    // Kept to reset the memory
+    rom: Vec<u8>,
+    // Source of Cxkk, can be seeded for reproducible runs
+    seed: Option<u64>,
+    rng: StdRng,
 }
 
 impl Chip8 {
-    pub fn new(rom: Vec<u8>) -> Self {
-        let mut memory = [0u8; MEMORY_SIZE];
-        for (i, font_data) in FONT_SET.iter().enumerate() {
-            memory[FONT_SET_ADDRESS_START + i] = *font_data;
-        }
-        for (i, rom_data) in rom.iter().enumerate() {
-            memory[START_PROGRAM_SPACE + i] = *rom_data;
-        }
+    // Fails when the ROM does not fit in memory
+    pub fn new(rom: Vec<u8>) -> Result<Self, Box<dyn Error>> {
+        check_rom_size(&rom, START_PROGRAM_SPACE)?;
 
-        Chip8 {
+        let mut chip = Chip8 {
             registers: [0u8; NUMBER_OF_REGISTER],
-            memory,
+            memory: [0u8; MEMORY_SIZE],
+            decoded: vec![None; MEMORY_SIZE],
+            cache_instructions: true,
+            program_start: START_PROGRAM_SPACE,
+            overflow: MemoryOverflow::Wrap,
+            font: Font::from_name("default").ok_or("no default font")?,
+            font_address: FONT_SET_ADDRESS_START,
             index: 0,
             program_counter: START_PROGRAM_SPACE as u16,
-            stack: [0; STACK_SIZE],
-            stack_pointer: 0,
+            stack: Vec::with_capacity(SCHIP_STACK_DEPTH),
+            stack_depth: SCHIP_STACK_DEPTH,
             delay_timer: 0,
             sound_timer: 0,
+            audio_pattern: None,
+            pitch: DEFAULT_PITCH,
             keypad: [0; NUMBER_OF_KEYS],
+            key_edges: KeyEdges::default(),
+            key_wait: KeyWaitMode::Release,
+            waiting_release: None,
             display: [0; DISPLAY_SIZE],
+            rom,
+            seed: None,
+            rng: StdRng::from_entropy(),
+        };
+        chip.reset();
+        Ok(chip)
+    }
+
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = Some(seed);
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    // Loads the ROM at another address and resets, fails when the ROM does not fit from there
+    pub fn set_program_start(&mut self, address: usize) -> Result<(), Box<dyn Error>> {
+        check_rom_size(&self.rom, address)?;
+        self.program_start = address;
+        self.reset();
+        Ok(())
+    }
+
+    // Replaces the font and resets, fails when the font does not fit in memory from `address`
+    pub fn set_font(&mut self, font: Font, address: usize) -> Result<(), Box<dyn Error>> {
+        if address + FONT_SIZE > MEMORY_SIZE {
+            return Err(format!("the font is {} bytes, it does not fit in memory from 0x{:03X}", FONT_SIZE, address).into());
+        }
+        self.font = font;
+        self.font_address = address;
+        self.reset();
+        Ok(())
+    }
+
+    // Decoding every instruction again when disabled, e.g. to compare the speed
+    pub fn set_instruction_cache(&mut self, enabled: bool) {
+        self.cache_instructions = enabled;
+        self.decoded.iter_mut().for_each(|instruction| *instruction = None);
+    }
+
+    pub fn set_stack_depth(&mut self, depth: usize) {
+        self.stack_depth = depth;
+    }
+
+    // Return addresses of the nested calls, the innermost last, e.g. for a debugger
+    pub fn call_stack(&self) -> &[u16] {
+        &self.stack
+    }
+
+    pub fn set_memory_overflow(&mut self, overflow: MemoryOverflow) {
+        self.overflow = overflow;
+    }
+
+    // Back to the state right after loading the ROM
+    pub fn reset(&mut self) {
+        self.memory = [0u8; MEMORY_SIZE];
+        self.decoded.iter_mut().for_each(|instruction| *instruction = None);
+        for (i, font_data) in self.font.small.iter().chain(self.font.big.iter()).enumerate() {
+            self.memory[self.font_address + i] = *font_data;
+        }
+        for (i, rom_data) in self.rom.iter().enumerate() {
+            self.memory[self.program_start + i] = *rom_data;
+        }
+        self.warm_reset();
+    }
+
+    // Restarts the ROM but keeps the memory, e.g. high scores or code the ROM modified
+    pub fn warm_reset(&mut self) {
+        self.registers = [0u8; NUMBER_OF_REGISTER];
+        self.index = 0;
+        self.program_counter = self.program_start as u16;
+        self.stack.clear();
+        self.waiting_release = None;
+        self.key_edges = KeyEdges::default();
+        self.delay_timer = 0;
+        self.sound_timer = 0;
+        self.audio_pattern = None;
+        self.pitch = DEFAULT_PITCH;
+        self.display = [0; DISPLAY_SIZE];
+        // Same random numbers as the first run when seeded
+        if let Some(seed) = self.seed {
+            self.rng = StdRng::seed_from_u64(seed);
         }
     }
 
-    pub fn get_display(&self) -> [u32; DISPLAY_SIZE] {
+    pub fn get_display(&self) -> [u8; DISPLAY_SIZE] {
         self.display
     }
 
@@ -206,27 +481,156 @@ impl Chip8 {
         }
     }
 
-    pub fn step(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let opcode = self.fetch();
-        let instruction = Self::decode(opcode).ok_or(format!("invalid opcode {:X}", opcode))?;
-        self.execute(instruction);
-        Ok(())
+    pub fn step(&mut self) -> Result<InstructionCost, Box<dyn Error>> {
+        let address = self.program_counter;
+        let instruction = match self.decoded.get(address as usize).copied().flatten() {
+            Some(instruction) => {
+                self.jump_to(address.wrapping_add(2));
+                instruction
+            }
+            None => {
+                let opcode = self.fetch()?;
+                let instruction = Self::decode(opcode).ok_or(Fault::InvalidOpcode { address, opcode })?;
+                if self.cache_instructions {
+                    if let Some(cached) = self.decoded.get_mut(address as usize) {
+                        *cached = Some(instruction);
+                    }
+                }
+                instruction
+            }
+        };
+        let cost = self.cost(&instruction);
+        if let Instruction::WaitKeyPress(_) = instruction {} else {
+            // Only the edges during Fx0A count
+            self.key_edges = KeyEdges::default();
+        }
+        self.execute(instruction, address)?;
+        Ok(cost)
+    }
+
+    // Keys past F are ignored
+    pub fn key_down(&mut self, key: usize) {
+        if key >= NUMBER_OF_KEYS {
+            return;
+        }
+        if self.keypad[key] != KEY_PRESSED {
+            self.key_edges.pressed |= 1 << key;
+        }
+        self.keypad[key] = KEY_PRESSED;
+    }
+
+    pub fn key_up(&mut self, key: usize) {
+        if key >= NUMBER_OF_KEYS {
+            return;
+        }
+        if self.keypad[key] == KEY_PRESSED {
+            self.key_edges.released |= 1 << key;
+        }
+        self.keypad[key] = KEY_NOT_PRESSED;
     }
 
-    pub fn set_keypad(&mut self, keys: [u8; 16]) {
-        self.keypad = keys;
+    #[cfg(test)]
+    pub fn is_key_pressed(&self, key: usize) -> bool {
+        self.keypad[key] == KEY_PRESSED
+    }
+
+    pub fn release_keys(&mut self) {
+        for key in 0..NUMBER_OF_KEYS {
+            self.key_up(key);
+        }
+    }
+
+    pub fn set_key_wait(&mut self, mode: KeyWaitMode) {
+        self.key_wait = mode;
     }
 
     pub fn is_playing_sound(&self) -> bool {
         self.sound_timer > 0
     }
 
-    fn fetch(&mut self) -> u16 {
-        let higher_byte = self.memory[self.program_counter as usize];
-        let lower_byte = self.memory[(self.program_counter + 1) as usize];
-        self.program_counter += 2;
+    pub fn get_audio_pattern(&self) -> Option<AudioPattern> {
+        self.audio_pattern.map(|buffer| AudioPattern { buffer, pitch: self.pitch })
+    }
+
+    fn fetch(&mut self) -> Result<u16, Box<dyn Error>> {
+        let address = self.program_counter;
+        let higher_byte = self.read(address as usize, address)?;
+        let lower_byte = self.read(address as usize + 1, address)?;
+        self.jump_to(address.wrapping_add(2));
 
-        ((higher_byte as u16) << 8) | (lower_byte as u16)
+        Ok(((higher_byte as u16) << 8) | (lower_byte as u16))
+    }
+
+    // Fx0A, the key once the wait is over
+    fn pressed_key(&mut self) -> Option<usize> {
+        let held = |keypad: &[u8; NUMBER_OF_KEYS], k: usize| keypad[k] == KEY_PRESSED;
+        match (self.key_wait, self.waiting_release) {
+            (KeyWaitMode::Press, _) => (0..NUMBER_OF_KEYS).find(|k| held(&self.keypad, *k)),
+            (KeyWaitMode::Release, None) => {
+                // Also a key pressed and released since the last instruction
+                let key = (0..NUMBER_OF_KEYS)
+                    .find(|k| held(&self.keypad, *k) || self.key_edges.pressed & (1 << k) != 0)?;
+                self.key_edges = KeyEdges::default();
+                self.waiting_release = Some(key);
+                self.pressed_key()
+            }
+            (KeyWaitMode::Release, Some(key)) => {
+                if held(&self.keypad, key) && self.key_edges.released & (1 << key) == 0 {
+                    return None;
+                }
+                self.waiting_release = None;
+                self.key_edges = KeyEdges::default();
+                Some(key)
+            }
+        }
+    }
+
+    // `instruction_address` is the address of the instruction reading, reported on faults
+    fn read(&self, address: usize, instruction_address: u16) -> Result<u8, Box<dyn Error>> {
+        Ok(self.memory[self.address(address, instruction_address)?])
+    }
+
+    fn write(&mut self, address: usize, value: u8, instruction_address: u16) -> Result<(), Box<dyn Error>> {
+        let address = self.address(address, instruction_address)?;
+        self.memory[address] = value;
+        // The instructions starting at this byte and at the one before
+        self.decoded[address] = None;
+        self.decoded[(address + MEMORY_SIZE - 1) % MEMORY_SIZE] = None;
+        Ok(())
+    }
+
+    fn address(&self, address: usize, instruction_address: u16) -> Result<usize, Box<dyn Error>> {
+        match self.overflow {
+            MemoryOverflow::Wrap => Ok(address % MEMORY_SIZE),
+            MemoryOverflow::Error if address < MEMORY_SIZE => Ok(address),
+            MemoryOverflow::Error => Err(Fault::MemoryOutOfBounds {
+                address: instruction_address,
+                access: address,
+            }.into()),
+        }
+    }
+
+    // Sets the address of the next instruction, kept in memory with MemoryOverflow::Wrap
+    fn jump_to(&mut self, address: u16) {
+        self.program_counter = match self.overflow {
+            MemoryOverflow::Wrap => address % MEMORY_SIZE as u16,
+            MemoryOverflow::Error => address,
+        };
+    }
+
+    fn cost(&self, instruction: &Instruction) -> InstructionCost {
+        let mut machine_cycles = instruction.vip_machine_cycles();
+        let mut wait_for_vblank = false;
+        if let Instruction::DisplaySprite(x, _, n) = instruction {
+            let row_cycles = if self.registers[*x] & 0x7 == 0 {
+                VIP_SPRITE_ROW_CYCLES
+            } else {
+                VIP_SPRITE_ROW_CYCLES + VIP_UNALIGNED_SPRITE_ROW_CYCLES
+            };
+            machine_cycles += row_cycles * *n as u32;
+            wait_for_vblank = true;
+        }
+        InstructionCost { machine_cycles, wait_for_vblank }
     }
 
     fn decode(opcode: u16) -> Option<Instruction> {
@@ -285,13 +689,16 @@ impl Chip8 {
             }
             0xF000..=0xFFFF => {
                 match opcode & 0xFF {
+                    0x02 if x == 0 => Some(Instruction::LoadAudioPattern),
                     0x07 => Some(Instruction::LoadTimer(x)),
                     0x0A => Some(Instruction::WaitKeyPress(x)),
                     0x15 => Some(Instruction::SetTimer(x)),
                     0x18 => Some(Instruction::SetSoundTimer(x)),
                     0x1E => Some(Instruction::AddIndex(x)),
                     0x29 => Some(Instruction::LoadSpriteLocationIndex(x)),
+                    0x30 => Some(Instruction::LoadBigSpriteLocationIndex(x)),
                     0x33 => Some(Instruction::BinaryCodedDecimal(x)),
+                    0x3A => Some(Instruction::SetPitch(x)),
                     0x55 => Some(Instruction::StoreRegisters(x)),
                     0x65 => Some(Instruction::ReadRegisters(x)),
                     _ => None
@@ -321,36 +728,38 @@ impl Chip8 {
         (opcode & 0xFF) as u8
     }
 
-    fn execute(&mut self, instruction: Instruction) {
+    fn execute(&mut self, instruction: Instruction, address: u16) -> Result<(), Box<dyn Error>> {
         match instruction {
             Instruction::Clear => {
                 self.display = [0; DISPLAY_SIZE];
             }
             Instruction::Return => {
-                self.program_counter = self.stack[self.stack_pointer];
-                self.stack_pointer -= 1;
+                let return_address = self.stack.pop().ok_or(Fault::StackUnderflow { address })?;
+                self.jump_to(return_address);
             }
             Instruction::Jump(nnn) => {
-                self.program_counter = nnn;
+                self.jump_to(nnn);
             }
             Instruction::Call(nnn) => {
-                self.stack_pointer += 1;
-                self.stack[self.stack_pointer] = self.program_counter;
-                self.program_counter = nnn;
+                if self.stack.len() >= self.stack_depth {
+                    return Err(Fault::StackOverflow { address, depth: self.stack_depth }.into());
+                }
+                self.stack.push(self.program_counter);
+                self.jump_to(nnn);
             }
             Instruction::SkipNextIfEqualByte(x, kk) => {
                 if self.registers[x] == kk {
-                    self.program_counter += 2;
+                    self.jump_to(self.program_counter.wrapping_add(2));
                 }
             }
             Instruction::SkipNextIfNotEqualByte(x, kk) => {
                 if self.registers[x] != kk {
-                    self.program_counter += 2;
+                    self.jump_to(self.program_counter.wrapping_add(2));
                 }
             }
             Instruction::SkipNextIfEqualRegister(x, y) => {
                 if self.registers[x] == self.registers[y] {
-                    self.program_counter += 2;
+                    self.jump_to(self.program_counter.wrapping_add(2));
                 }
             }
             Instruction::LoadByte(x, kk) => {
@@ -430,17 +839,17 @@ impl Chip8 {
             }
             Instruction::SkipNextIfNotEqualRegister(x, y) => {
                 if self.registers[x] != self.registers[y] {
-                    self.program_counter += 2;
+                    self.jump_to(self.program_counter.wrapping_add(2));
                 }
             }
             Instruction::SetIndex(nnn) => {
                 self.index = nnn;
             }
             Instruction::JumpOf(nnn) => {
-                self.program_counter = nnn + self.registers[0] as u16;
+                self.jump_to(nnn + self.registers[0] as u16);
             }
             Instruction::Random(x, kk) => {
-                let rng = rand::thread_rng().next_u32() as u8;
+                let rng = self.rng.next_u32() as u8;
                 self.registers[x] = rng & kk;
             }
             Instruction::DisplaySprite(x, y, n) => {
@@ -452,8 +861,8 @@ impl Chip8 {
                 let index = self.index as usize;
                 for j in 0..n as usize {
                     let mut mask = 0x80;
-                    let sprite_line = self.memory[index + j];
-                    for i in 0..8 as usize {
+                    let sprite_line = self.read(index + j, address)?;
+                    for i in 0..8 {
                         let pixel_value = if sprite_line & mask > 0 {
                             PIXEL_ON
                         } else {
@@ -476,27 +885,24 @@ impl Chip8 {
             Instruction::SkipIfKeyPressed(x) => {
                 let key = self.registers[x] as usize;
                 if self.keypad[key] == KEY_PRESSED {
-                    self.program_counter += 2;
+                    self.jump_to(self.program_counter.wrapping_add(2));
                 }
             }
             Instruction::SkipIfNotKeyPressed(x) => {
                 let key = self.registers[x] as usize;
                 if self.keypad[key] == KEY_NOT_PRESSED {
-                    self.program_counter += 2;
+                    self.jump_to(self.program_counter.wrapping_add(2));
                 }
             }
             Instruction::LoadTimer(x) => {
                 self.registers[x] = self.delay_timer;
             }
             Instruction::WaitKeyPress(x) => {
-                for (k, status) in self.keypad.iter().enumerate() {
-                    if *status == KEY_PRESSED {
-                        self.registers[x] = k as u8;
-                        return;
-                    }
+                if let Some(key) = self.pressed_key() {
+                    self.registers[x] = key as u8;
+                } else {
+                    self.jump_to(address);
                 }
-
-                self.program_counter -= 2;
             }
             Instruction::SetTimer(x) => {
                 self.delay_timer = self.registers[x];
@@ -504,12 +910,27 @@ impl Chip8 {
             Instruction::SetSoundTimer(x) => {
                 self.sound_timer = self.registers[x];
             }
+            Instruction::LoadAudioPattern => {
+                let index = self.index as usize;
+                let mut pattern = [0u8; AUDIO_PATTERN_SIZE];
+                for (i, byte) in pattern.iter_mut().enumerate() {
+                    *byte = self.read(index + i, address)?;
+                }
+                self.audio_pattern = Some(pattern);
+            }
+            Instruction::SetPitch(x) => {
+                self.pitch = self.registers[x];
+            }
             Instruction::AddIndex(x) => {
-                self.index += self.registers[x] as u16;
+                self.index = self.index.wrapping_add(self.registers[x] as u16);
             }
             Instruction::LoadSpriteLocationIndex(x) => {
                 let vx = self.registers[x];
-                self.index = (FONT_SET_ADDRESS_START + (vx as usize) * NUMBER_OF_BYTES_PER_CHARACTER) as u16;
+                self.index = (self.font_address + (vx as usize) * SMALL_CHARACTER_SIZE) as u16;
+            }
+            Instruction::LoadBigSpriteLocationIndex(x) => {
+                let vx = self.registers[x];
+                self.index = (self.font_address + SMALL_FONT_SIZE + (vx as usize) * BIG_CHARACTER_SIZE) as u16;
             }
             Instruction::BinaryCodedDecimal(x) => {
                 let vx: Vec<char> = format!("{:0>3}", self.registers[x]).chars().collect();
@@ -518,20 +939,168 @@ impl Chip8 {
                 let ones: u8 = vx[2].to_string().parse().unwrap();
 
                 let index = self.index as usize;
-                self.memory[index] = hundreds;
-                self.memory[index + 1] = tens;
-                self.memory[index + 2] = ones;
+                self.write(index, hundreds, address)?;
+                self.write(index + 1, tens, address)?;
+                self.write(index + 2, ones, address)?;
             }
             Instruction::StoreRegisters(x) => {
-                for i in 0..=x as usize {
-                    self.memory[(self.index as usize) + i] = self.registers[i];
+                for i in 0..=x {
+                    self.write(self.index as usize + i, self.registers[i], address)?;
                 }
             }
             Instruction::ReadRegisters(x) => {
-                for i in 0..=x as usize {
-                    self.registers[i] = self.memory[(self.index as usize) + i];
+                for i in 0..=x {
+                    self.registers[i] = self.read(self.index as usize + i, address)?;
                 }
             }
         };
+        Ok(())
+    }
+}
+
+fn check_rom_size(rom: &[u8], program_start: usize) -> Result<(), Box<dyn Error>> {
+    let max_size = MEMORY_SIZE.saturating_sub(program_start);
+    if rom.len() > max_size {
+        return Err(format!("the ROM is {} bytes, at most {} bytes fit in memory from 0x{:03X}",
+                           rom.len(), max_size, program_start).into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fault(error: Box<dyn Error>) -> Fault {
+        *error.downcast_ref::<Fault>().expect("a fault")
+    }
+
+    #[test]
+    fn fetch_fault_reports_the_fetched_address() {
+        let mut chip = Chip8::new(vec![0x60, 0x01]).unwrap();
+        chip.set_program_start(0xFFE).unwrap();
+        chip.set_memory_overflow(MemoryOverflow::Error);
+        chip.step().unwrap();
+        let error = chip.step().unwrap_err();
+        assert_eq!(fault(error), Fault::MemoryOutOfBounds { address: 0x1000, access: 0x1000 });
+    }
+
+    #[test]
+    fn access_fault_reports_the_instruction_address() {
+        // i := 0xFFF, load v1
+        let mut chip = Chip8::new(vec![0xAF, 0xFF, 0xF1, 0x65]).unwrap();
+        chip.set_memory_overflow(MemoryOverflow::Error);
+        chip.step().unwrap();
+        let error = chip.step().unwrap_err();
+        assert_eq!(fault(error), Fault::MemoryOutOfBounds { address: 0x202, access: 0x1000 });
+    }
+
+    #[test]
+    fn program_counter_wraps() {
+        // v0 := 1, then v1 := 2 from address 0, cached the second time
+        let mut chip = Chip8::new(vec![0x60, 0x01]).unwrap();
+        chip.set_program_start(0xFFE).unwrap();
+        chip.memory[0] = 0x61;
+        chip.memory[1] = 0x02;
+        chip.memory[2] = 0x1F;
+        chip.memory[3] = 0xFE;
+        for _ in 0..2 {
+            chip.step().unwrap();
+            assert_eq!(chip.program_counter, 0x000);
+            chip.step().unwrap();
+            chip.step().unwrap();
+        }
+        assert_eq!(chip.registers[..2], [1, 2]);
+    }
+
+    // Opcodes at their addresses, from 0x200
+    fn program(opcodes: &[(usize, u16)]) -> Chip8 {
+        let mut rom = vec![0; 0x30];
+        for (address, opcode) in opcodes {
+            let offset = address - START_PROGRAM_SPACE;
+            rom[offset] = (opcode >> 8) as u8;
+            rom[offset + 1] = *opcode as u8;
+        }
+        Chip8::new(rom).unwrap()
+    }
+
+    fn run(chip: &mut Chip8, steps: usize) {
+        for _ in 0..steps {
+            chip.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn stored_registers_replace_executed_instructions() {
+        // v1 := 5, then v0 replaces its high byte (v1 += 5) or its low byte (v1 := 9)
+        for (target, value, expected) in [(0x200, 0x71, 10), (0x201, 0x09, 9)].iter() {
+            let mut chip = program(&[
+                (0x200, 0x6105),
+                (0x202, 0xA000 | target),
+                (0x204, 0x6000 | value),
+                (0x206, 0xF055),
+                (0x208, 0x1200),
+            ]);
+            run(&mut chip, 6);
+            assert_eq!(chip.registers[1], *expected);
+        }
+    }
+
+    #[test]
+    fn binary_coded_decimal_replaces_executed_instructions() {
+        // v1 := 5 becomes v1 := 0 once bcd writes 0, 0, 7 from its low byte
+        let mut chip = program(&[
+            (0x200, 0x6207),
+            (0x202, 0x1210),
+            (0x210, 0x6105),
+            (0x212, 0x1220),
+            (0x220, 0xA211),
+            (0x222, 0xF233),
+            (0x224, 0x1210),
+        ]);
+        run(&mut chip, 3);
+        assert_eq!(chip.registers[1], 5);
+        run(&mut chip, 5);
+        assert_eq!(chip.registers[1], 0);
+        assert_eq!(chip.memory[0x213], 7);
+    }
+
+    // v0 := key, then waits forever
+    fn wait_key(mode: KeyWaitMode) -> Chip8 {
+        let mut chip = program(&[(0x200, 0xF00A), (0x202, 0x1202)]);
+        chip.set_key_wait(mode);
+        chip
+    }
+
+    #[test]
+    fn wait_key_completes_on_release() {
+        let mut chip = wait_key(KeyWaitMode::Release);
+        run(&mut chip, 2);
+        assert_eq!(chip.program_counter, 0x200);
+        chip.key_down(5);
+        run(&mut chip, 3);
+        assert_eq!(chip.program_counter, 0x200);
+        chip.key_up(5);
+        run(&mut chip, 1);
+        assert_eq!((chip.program_counter, chip.registers[0]), (0x202, 5));
+    }
+
+    #[test]
+    fn wait_key_sees_a_tap_between_instructions() {
+        let mut chip = wait_key(KeyWaitMode::Release);
+        run(&mut chip, 1);
+        chip.key_down(7);
+        chip.key_up(7);
+        run(&mut chip, 1);
+        assert_eq!((chip.program_counter, chip.registers[0]), (0x202, 7));
+    }
+
+    #[test]
+    fn wait_key_completes_on_press() {
+        let mut chip = wait_key(KeyWaitMode::Press);
+        run(&mut chip, 1);
+        chip.key_down(9);
+        run(&mut chip, 1);
+        assert_eq!((chip.program_counter, chip.registers[0]), (0x202, 9));
     }
 }
@@ -0,0 +1,70 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+
+const CONFIG_EXTENSION: &str = "cfg";
+
+/*
+ * Per ROM settings, read from a file next to the ROM (e.g. rom/BRIX.cfg)
+ * Each line is `option = value`, using the command line option names.
+ * Lines starting with # are comments.
+ * Options given on the command line take precedence.
+ */
+pub struct RomConfig {
+    values: HashMap<String, String>,
+}
+
+impl RomConfig {
+    // Fails on the options not in `options`, e.g. a typo
+    pub fn load(rom_path: &str, options: &[&str]) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = format!("{}.{}", rom_path, CONFIG_EXTENSION);
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut values = HashMap::new();
+        for (number, key, value) in parse_lines(&content, &path)? {
+            if !options.contains(&key.as_str()) {
+                return Err(format!("{}:{}: unknown option {}", path, number, key).into());
+            }
+            values.insert(key, value);
+        }
+        Ok(RomConfig { values })
+    }
+
+    // Options packaged with the ROM, e.g. in an Octo cartridge, the config file takes precedence
+    pub fn add_defaults(&mut self, options: HashMap<String, String>) {
+        for (key, value) in options {
+            self.values.entry(key).or_insert(value);
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(|value| value.as_str())
+    }
+}
+
+// Lines of `key = value`, # starts a comment line, `path` is used in errors
+pub fn parse_values(content: &str, path: &str) -> Result<HashMap<String, String>, String> {
+    Ok(parse_lines(content, path)?.into_iter().map(|(_, key, value)| (key, value)).collect())
+}
+
+// Line number, key and value of each `key = value` line
+fn parse_lines(content: &str, path: &str) -> Result<Vec<(usize, String, String)>, String> {
+    let mut values = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let mut parts = line.splitn(2, '=');
+        let key = parts.next().unwrap_or("").trim();
+        let value = parts.next()
+            .ok_or(format!("{}:{}: expected `option = value`", path, number + 1))?
+            .trim();
+        values.push((number + 1, key.to_string(), value.to_string()));
+    }
+    Ok(values)
+}
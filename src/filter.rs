@@ -0,0 +1,135 @@
+use crate::palette::{blend, mix};
+
+pub const FILTER_NAMES: [&str; 4] = ["scanlines", "grid", "bloom", "rounded"];
+
+const SCANLINE_BRIGHTNESS: f32 = 0.6;
+const GRID_BRIGHTNESS: f32 = 0.75;
+const BLOOM_STRENGTH: f32 = 0.4;
+const BLACK: u32 = 0;
+
+// Post-processing applied to the scaled buffer, where each emulated pixel is a `scale` x `scale` cell
+pub trait Filter {
+    fn apply(&self, buffer: &mut [u32], width: usize, height: usize, scale: usize);
+}
+
+// Darkens every other line of the buffer
+pub struct Scanlines;
+
+impl Filter for Scanlines {
+    fn apply(&self, buffer: &mut [u32], width: usize, height: usize, _scale: usize) {
+        for j in (1..height).step_by(2) {
+            for pixel in &mut buffer[j * width..(j + 1) * width] {
+                *pixel = mix(*pixel, BLACK, SCANLINE_BRIGHTNESS);
+            }
+        }
+    }
+}
+
+// Darkens the right and bottom edges of each cell
+pub struct PixelGrid;
+
+impl Filter for PixelGrid {
+    fn apply(&self, buffer: &mut [u32], width: usize, height: usize, scale: usize) {
+        if scale < 3 {
+            return;
+        }
+        for j in 0..height {
+            for i in 0..width {
+                if i % scale == scale - 1 || j % scale == scale - 1 {
+                    let pixel = &mut buffer[i + j * width];
+                    *pixel = mix(*pixel, BLACK, GRID_BRIGHTNESS);
+                }
+            }
+        }
+    }
+}
+
+// Adds a blurred copy of the buffer on top of itself
+pub struct Bloom;
+
+impl Filter for Bloom {
+    fn apply(&self, buffer: &mut [u32], width: usize, height: usize, scale: usize) {
+        let radius = (scale / 2).max(1);
+        let horizontal = box_blur(buffer, width, height, radius, 1, width);
+        let blurred = box_blur(&horizontal, width, height, radius, width, 1);
+        for (pixel, glow) in buffer.iter_mut().zip(blurred.iter()) {
+            *pixel = blend(*pixel, *glow, 1.0, BLOOM_STRENGTH);
+        }
+    }
+}
+
+// Gives each cell's corners the color of its two neighbours on that corner when they agree
+pub struct RoundedPixels;
+
+impl Filter for RoundedPixels {
+    fn apply(&self, buffer: &mut [u32], width: usize, height: usize, scale: usize) {
+        if scale < 4 {
+            return;
+        }
+        let original = buffer.to_vec();
+        let cells_x = width / scale;
+        let cells_y = height / scale;
+        let cell_color = |ci: usize, cj: usize| original[(ci * scale + scale / 2) + (cj * scale + scale / 2) * width];
+        let radius = scale as f32 / 2.0;
+
+        for cj in 0..cells_y {
+            for ci in 0..cells_x {
+                for dj in 0..scale {
+                    for di in 0..scale {
+                        let dx = di as f32 + 0.5 - radius;
+                        let dy = dj as f32 + 0.5 - radius;
+                        if dx.abs() + dy.abs() <= radius * 1.25 {
+                            continue;
+                        }
+                        let side = if dx < 0.0 { (ci + cells_x - 1) % cells_x } else { (ci + 1) % cells_x };
+                        let vertical = if dy < 0.0 { (cj + cells_y - 1) % cells_y } else { (cj + 1) % cells_y };
+                        let side_color = cell_color(side, cj);
+                        if side_color == cell_color(ci, vertical) {
+                            buffer[(ci * scale + di) + (cj * scale + dj) * width] = side_color;
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub fn parse_filters(value: &str) -> Result<Vec<Box<dyn Filter>>, String> {
+    value.split(',')
+        .map(|name| name.trim())
+        .filter(|name| !name.is_empty() && *name != "none")
+        .map(|name| -> Result<Box<dyn Filter>, String> {
+            match name {
+                "scanlines" => Ok(Box::new(Scanlines)),
+                "grid" => Ok(Box::new(PixelGrid)),
+                "bloom" => Ok(Box::new(Bloom)),
+                "rounded" => Ok(Box::new(RoundedPixels)),
+                _ => Err(format!("invalid filter {}, expected one of {}", name, FILTER_NAMES.join(", ")))
+            }
+        })
+        .collect()
+}
+
+// Averages each pixel with its neighbours within `radius` steps of `stride`, along lines of `length` pixels
+fn box_blur(buffer: &[u32], width: usize, height: usize, radius: usize, stride: usize, line_stride: usize) -> Vec<u32> {
+    let (lines, length) = if stride == 1 { (height, width) } else { (width, height) };
+    let mut blurred = vec![0; buffer.len()];
+    for line in 0..lines {
+        let start = line * line_stride;
+        for position in 0..length {
+            let from = position.saturating_sub(radius);
+            let to = (position + radius).min(length - 1);
+            let mut sums = [0u32; 3];
+            for k in from..=to {
+                let pixel = buffer[start + k * stride];
+                for (channel, sum) in sums.iter_mut().enumerate() {
+                    *sum += (pixel >> (channel * 8)) & 0xFF;
+                }
+            }
+            let count = (to - from + 1) as u32;
+            blurred[start + position * stride] = sums.iter().enumerate()
+                .fold(0, |acc, (channel, sum)| acc | ((sum / count) << (channel * 8)));
+        }
+    }
+    blurred
+}
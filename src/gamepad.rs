@@ -0,0 +1,461 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+
+use crate::chip8::NUMBER_OF_KEYS;
+use crate::scheduler::{KeySource, Scheduler};
+
+// d-pad on the usual keypad directions, the stick follows the d-pad
+pub const DEFAULT_GAMEPAD_MAP: &str = "up=2,down=8,left=4,right=6,south=5,east=0,start=F";
+
+// Stick deflection, from -1 to 1, past which a stick direction is pressed
+const STICK_THRESHOLD: f32 = 0.5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Button {
+    Up,
+    Down,
+    Left,
+    Right,
+    South,
+    East,
+    North,
+    West,
+    Start,
+    Select,
+    L1,
+    R1,
+    L2,
+    R2,
+    // Left stick directions, pressed past STICK_THRESHOLD
+    StickUp,
+    StickDown,
+    StickLeft,
+    StickRight,
+}
+
+const BUTTON_NAMES: [(&str, Button); 18] = [
+    ("up", Button::Up),
+    ("down", Button::Down),
+    ("left", Button::Left),
+    ("right", Button::Right),
+    ("south", Button::South),
+    ("east", Button::East),
+    ("north", Button::North),
+    ("west", Button::West),
+    ("start", Button::Start),
+    ("select", Button::Select),
+    ("l1", Button::L1),
+    ("r1", Button::R1),
+    ("l2", Button::L2),
+    ("r2", Button::R2),
+    ("stick-up", Button::StickUp),
+    ("stick-down", Button::StickDown),
+    ("stick-left", Button::StickLeft),
+    ("stick-right", Button::StickRight),
+];
+
+impl Button {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        BUTTON_NAMES.iter()
+            .find(|(name, _)| *name == value)
+            .map(|(_, button)| *button)
+            .ok_or(format!("invalid gamepad button {}", value))
+    }
+
+    // d-pad direction followed by a stick direction that is not mapped
+    fn dpad(&self) -> Option<Button> {
+        match self {
+            Button::StickUp => Some(Button::Up),
+            Button::StickDown => Some(Button::Down),
+            Button::StickLeft => Some(Button::Left),
+            Button::StickRight => Some(Button::Right),
+            _ => None
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Axis {
+    // Left stick, -1 is left
+    StickX,
+    // Left stick, -1 is down
+    StickY,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GamepadEvent {
+    Pressed(Button),
+    Released(Button),
+    Moved(Axis, f32),
+}
+
+/*
+ * Source of gamepad events, a controller or a simulated one
+ * Returns the events until `until` seconds of emulated time, with the emulated time they
+ * happened at, or None for events happening now.
+ */
+pub trait GamepadDevice {
+    fn poll(&mut self, until: f64) -> Vec<(Option<f64>, GamepadEvent)>;
+}
+
+/*
+ * Gamepad buttons of the keypad, e.g. `up=2,down=8,south=5`
+ * Each button is mapped to a hexadecimal keypad value, several buttons can share a key.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub struct GamepadMapping {
+    keys: HashMap<Button, usize>,
+}
+
+impl GamepadMapping {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut keys = HashMap::new();
+        for pair in value.split(',').map(|pair| pair.trim()).filter(|pair| !pair.is_empty()) {
+            let mut parts = pair.splitn(2, '=');
+            let button = Button::parse(parts.next().unwrap_or("").trim())?;
+            let key = parts.next()
+                .and_then(|key| usize::from_str_radix(key.trim(), 16).ok())
+                .filter(|key| *key < NUMBER_OF_KEYS)
+                .ok_or(format!("invalid gamepad mapping {}, expected `button=key` with a key from 0 to F", pair))?;
+            keys.insert(button, key);
+        }
+        Ok(GamepadMapping { keys })
+    }
+
+    fn key(&self, button: Button) -> Option<usize> {
+        self.keys.get(&button).copied()
+            .or_else(|| button.dpad().and_then(|dpad| self.keys.get(&dpad).copied()))
+    }
+}
+
+/*
+ * Turns the events of a gamepad device into keypad events of the scheduler.
+ * A key is pressed while any of the buttons mapped to it is held.
+ */
+pub struct GamepadInput {
+    device: Box<dyn GamepadDevice>,
+    mapping: GamepadMapping,
+    held: Vec<Button>,
+}
+
+impl GamepadInput {
+    pub fn new(device: Box<dyn GamepadDevice>, mapping: GamepadMapping) -> Self {
+        GamepadInput { device, mapping, held: Vec::new() }
+    }
+
+    // Queues the keypad events until `horizon` seconds of emulated time from now
+    pub fn update(&mut self, scheduler: &mut Scheduler, horizon: f64) {
+        let now = scheduler.time();
+        for (time, event) in self.device.poll(now + horizon) {
+            let delay = time.map(|time| (time - now).max(0.0)).unwrap_or(0.0);
+            let changes = match event {
+                GamepadEvent::Pressed(button) => vec![(button, true)],
+                GamepadEvent::Released(button) => vec![(button, false)],
+                GamepadEvent::Moved(Axis::StickX, value) => vec![
+                    (Button::StickLeft, value < -STICK_THRESHOLD),
+                    (Button::StickRight, value > STICK_THRESHOLD),
+                ],
+                GamepadEvent::Moved(Axis::StickY, value) => vec![
+                    (Button::StickDown, value < -STICK_THRESHOLD),
+                    (Button::StickUp, value > STICK_THRESHOLD),
+                ],
+            };
+            for (button, pressed) in changes {
+                self.set_button(scheduler, button, pressed, delay);
+            }
+        }
+    }
+
+    // Forgets the held buttons, after the keypad was released, e.g. while the menu is open
+    pub fn release(&mut self) {
+        self.held.clear();
+    }
+
+    fn set_button(&mut self, scheduler: &mut Scheduler, button: Button, pressed: bool, delay: f64) {
+        if self.held.contains(&button) == pressed {
+            return;
+        }
+        let key = match self.mapping.key(button) {
+            Some(key) => key,
+            None => return,
+        };
+        let was_down = self.is_key_down(key);
+        if pressed {
+            self.held.push(button);
+        } else {
+            self.held.retain(|held| *held != button);
+        }
+        if self.is_key_down(key) != was_down {
+            scheduler.queue_key(KeySource::Gamepad, key, pressed, delay);
+        }
+    }
+
+    fn is_key_down(&self, key: usize) -> bool {
+        self.held.iter().any(|button| self.mapping.key(*button) == Some(key))
+    }
+}
+
+// Controllers connected to the host, the events of all of them are merged
+pub struct Controllers {
+    gilrs: gilrs::Gilrs,
+}
+
+impl Controllers {
+    pub fn new() -> Result<Self, Box<dyn std::error::Error>> {
+        let gilrs = match gilrs::Gilrs::new() {
+            Ok(gilrs) => gilrs,
+            // No gamepad support on this platform, no controller is ever connected
+            Err(gilrs::Error::NotImplemented(gilrs)) => gilrs,
+            Err(e) => return Err(format!("could not open the gamepads: {}", e).into()),
+        };
+        Ok(Controllers { gilrs })
+    }
+}
+
+impl GamepadDevice for Controllers {
+    fn poll(&mut self, _until: f64) -> Vec<(Option<f64>, GamepadEvent)> {
+        let mut events = Vec::new();
+        while let Some(gilrs::Event { event, .. }) = self.gilrs.next_event() {
+            let event = match event {
+                gilrs::EventType::ButtonPressed(button, _) => controller_button(button).map(GamepadEvent::Pressed),
+                gilrs::EventType::ButtonReleased(button, _) => controller_button(button).map(GamepadEvent::Released),
+                gilrs::EventType::AxisChanged(gilrs::Axis::LeftStickX, value, _) => Some(GamepadEvent::Moved(Axis::StickX, value)),
+                gilrs::EventType::AxisChanged(gilrs::Axis::LeftStickY, value, _) => Some(GamepadEvent::Moved(Axis::StickY, value)),
+                _ => None
+            };
+            events.extend(event.map(|event| (None, event)));
+        }
+        events
+    }
+}
+
+fn controller_button(button: gilrs::Button) -> Option<Button> {
+    match button {
+        gilrs::Button::DPadUp => Some(Button::Up),
+        gilrs::Button::DPadDown => Some(Button::Down),
+        gilrs::Button::DPadLeft => Some(Button::Left),
+        gilrs::Button::DPadRight => Some(Button::Right),
+        gilrs::Button::South => Some(Button::South),
+        gilrs::Button::East => Some(Button::East),
+        gilrs::Button::North => Some(Button::North),
+        gilrs::Button::West => Some(Button::West),
+        gilrs::Button::Start => Some(Button::Start),
+        gilrs::Button::Select => Some(Button::Select),
+        gilrs::Button::LeftTrigger => Some(Button::L1),
+        gilrs::Button::RightTrigger => Some(Button::R1),
+        gilrs::Button::LeftTrigger2 => Some(Button::L2),
+        gilrs::Button::RightTrigger2 => Some(Button::R2),
+        _ => None
+    }
+}
+
+/*
+ * Simulated gamepad replaying a script, to play or check ROMs without a controller
+ * Each line is `seconds input state`, in emulated time since the start, e.g.
+ *   0.5 south down
+ *   0.6 south up
+ *   1.0 stick-x -1
+ * The input is a button with `down` or `up`, or `stick-x` / `stick-y` with a value from -1 to 1.
+ * Lines starting with # are comments.
+ */
+pub struct ScriptedGamepad {
+    // By time, in script order for the same time
+    events: VecDeque<(f64, GamepadEvent)>,
+}
+
+impl ScriptedGamepad {
+    pub fn load(path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        let content = fs::read_to_string(path).map_err(|e| format!("could not read gamepad script {}: {}", path, e))?;
+        let mut events = Vec::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let event = parse_script_line(line).map_err(|e| format!("{}:{}: {}", path, number + 1, e))?;
+            events.push(event);
+        }
+        events.sort_by(|(a, _), (b, _)| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        Ok(ScriptedGamepad { events: events.into() })
+    }
+}
+
+impl GamepadDevice for ScriptedGamepad {
+    fn poll(&mut self, until: f64) -> Vec<(Option<f64>, GamepadEvent)> {
+        let mut events = Vec::new();
+        while let Some((time, event)) = self.events.front().copied() {
+            if time >= until {
+                break;
+            }
+            events.push((Some(time), event));
+            self.events.pop_front();
+        }
+        events
+    }
+}
+
+fn parse_script_line(line: &str) -> Result<(f64, GamepadEvent), String> {
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    if parts.len() != 3 {
+        return Err("expected `seconds input state`".to_string());
+    }
+    let time = parts[0].parse::<f64>().ok()
+        .filter(|time| *time >= 0.0)
+        .ok_or(format!("invalid time {}", parts[0]))?;
+    let value = || parts[2].parse::<f32>().map(|value| value.clamp(-1.0, 1.0))
+        .map_err(|_| format!("invalid stick value {}", parts[2]));
+    let event = match (parts[1], parts[2]) {
+        ("stick-x", _) => GamepadEvent::Moved(Axis::StickX, value()?),
+        ("stick-y", _) => GamepadEvent::Moved(Axis::StickY, value()?),
+        (button, "down") => GamepadEvent::Pressed(Button::parse(button)?),
+        (button, "up") => GamepadEvent::Released(Button::parse(button)?),
+        (_, state) => return Err(format!("invalid button state {}, expected down or up", state)),
+    };
+    Ok((time, event))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::audio::NullSink;
+    use crate::chip8::Chip8;
+    use crate::scheduler::TimingMode;
+
+    // Instructions per second, one cycle is a millisecond
+    const FREQUENCY: f64 = 1000.0;
+
+    // Events sent now, shared with the test
+    struct FakeGamepad {
+        events: Rc<RefCell<Vec<GamepadEvent>>>,
+    }
+
+    impl GamepadDevice for FakeGamepad {
+        fn poll(&mut self, _until: f64) -> Vec<(Option<f64>, GamepadEvent)> {
+            self.events.borrow_mut().drain(..).map(|event| (None, event)).collect()
+        }
+    }
+
+    struct Setup {
+        events: Rc<RefCell<Vec<GamepadEvent>>>,
+        input: GamepadInput,
+        scheduler: Scheduler,
+        // Jumping to itself
+        chip: Chip8,
+    }
+
+    impl Setup {
+        fn new(device: Option<Box<dyn GamepadDevice>>, mapping: &str) -> Self {
+            let events = Rc::new(RefCell::new(Vec::new()));
+            let device = device.unwrap_or_else(|| Box::new(FakeGamepad { events: events.clone() }));
+            Setup {
+                events,
+                input: GamepadInput::new(device, GamepadMapping::parse(mapping).unwrap()),
+                scheduler: Scheduler::new(FREQUENCY, TimingMode::Fixed),
+                chip: Chip8::new(vec![0x12, 0x00]).unwrap(),
+            }
+        }
+
+        // Sends the events and runs until `seconds` of emulated time since the start
+        fn run(&mut self, events: &[GamepadEvent], seconds: f64) {
+            self.events.borrow_mut().extend_from_slice(events);
+            self.input.update(&mut self.scheduler, 1.0);
+            let remaining = seconds - self.scheduler.time();
+            self.scheduler.run(&mut self.chip, remaining, &mut NullSink).unwrap();
+        }
+
+        fn step(&mut self, events: &[GamepadEvent]) {
+            let now = self.scheduler.time();
+            self.run(events, now + 2.0 / FREQUENCY);
+        }
+    }
+
+    #[test]
+    fn buttons_sharing_a_key() {
+        let mut setup = Setup::new(None, "south=5,east=5");
+        setup.step(&[GamepadEvent::Pressed(Button::South), GamepadEvent::Pressed(Button::East)]);
+        assert!(setup.chip.is_key_pressed(5));
+        setup.step(&[GamepadEvent::Released(Button::South)]);
+        assert!(setup.chip.is_key_pressed(5));
+        setup.step(&[GamepadEvent::Released(Button::East)]);
+        assert!(!setup.chip.is_key_pressed(5));
+    }
+
+    #[test]
+    fn gamepad_and_keyboard_sharing_a_key() {
+        let mut setup = Setup::new(None, "south=5");
+        setup.step(&[GamepadEvent::Pressed(Button::South)]);
+        setup.scheduler.key_down(KeySource::Keyboard, 5);
+        setup.scheduler.key_up(KeySource::Keyboard, 5);
+        setup.step(&[]);
+        assert!(setup.chip.is_key_pressed(5));
+        setup.step(&[GamepadEvent::Released(Button::South)]);
+        assert!(!setup.chip.is_key_pressed(5));
+    }
+
+    #[test]
+    fn stick_follows_the_dpad_past_the_threshold() {
+        let mut setup = Setup::new(None, DEFAULT_GAMEPAD_MAP);
+        setup.step(&[GamepadEvent::Moved(Axis::StickX, -0.3)]);
+        assert!(!setup.chip.is_key_pressed(4));
+        setup.step(&[GamepadEvent::Moved(Axis::StickX, -0.8)]);
+        assert!(setup.chip.is_key_pressed(4));
+        setup.step(&[GamepadEvent::Moved(Axis::StickX, 0.9)]);
+        assert!(!setup.chip.is_key_pressed(4));
+        assert!(setup.chip.is_key_pressed(6));
+        setup.step(&[GamepadEvent::Moved(Axis::StickX, 0.0), GamepadEvent::Moved(Axis::StickY, 1.0)]);
+        assert!(!setup.chip.is_key_pressed(6));
+        assert!(setup.chip.is_key_pressed(2));
+    }
+
+    #[test]
+    fn mapped_stick_direction() {
+        let mut setup = Setup::new(None, "up=2,stick-up=A");
+        setup.step(&[GamepadEvent::Moved(Axis::StickY, 0.7)]);
+        assert!(setup.chip.is_key_pressed(0xA));
+        assert!(!setup.chip.is_key_pressed(2));
+    }
+
+    #[test]
+    fn invalid_mappings() {
+        assert!(GamepadMapping::parse("south=G").is_err());
+        assert!(GamepadMapping::parse("south=10").is_err());
+        assert!(GamepadMapping::parse("south").is_err());
+        assert_eq!(GamepadMapping::parse("jump=1"), Err("invalid gamepad button jump".to_string()));
+    }
+
+    #[test]
+    fn invalid_script_lines() {
+        assert_eq!(parse_script_line("0.5 south down"), Ok((0.5, GamepadEvent::Pressed(Button::South))));
+        assert_eq!(parse_script_line("1 stick-x -2"), Ok((1.0, GamepadEvent::Moved(Axis::StickX, -1.0))));
+        assert!(parse_script_line("0.5 south").is_err());
+        assert!(parse_script_line("-1 south down").is_err());
+        assert!(parse_script_line("soon south down").is_err());
+        assert!(parse_script_line("0.5 south pressed").is_err());
+        assert!(parse_script_line("0.5 jump down").is_err());
+        assert!(parse_script_line("0.5 stick-y far").is_err());
+    }
+
+    #[test]
+    fn scripted_replay() {
+        let path = std::env::temp_dir().join(format!("chip8-gamepad-{}.txt", std::process::id()));
+        let script = "# pressed and released at once\n0.5 south down\n0.5 south up\n1.5 east up\n1.0 east down\n";
+        fs::write(&path, script).unwrap();
+        let gamepad = ScriptedGamepad::load(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+
+        let mut setup = Setup::new(Some(Box::new(gamepad.unwrap())), "south=5,east=0");
+        setup.run(&[], 0.6);
+        assert!(!setup.chip.is_key_pressed(5));
+        setup.run(&[], 0.99);
+        assert!(!setup.chip.is_key_pressed(0));
+        setup.run(&[], 1.01);
+        assert!(setup.chip.is_key_pressed(0));
+        setup.run(&[], 1.49);
+        assert!(setup.chip.is_key_pressed(0));
+        setup.run(&[], 1.51);
+        assert!(!setup.chip.is_key_pressed(0));
+    }
+}
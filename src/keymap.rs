@@ -0,0 +1,56 @@
+use minifb::{Key, KeyRepeat, Window};
+
+use crate::chip8::NUMBER_OF_KEYS;
+
+pub const KEYMAP_NAMES: [&str; 2] = ["qwerty", "azerty"];
+
+/*
+ * Keyboard keys of the keypad, by keypad value
+ * Keypad    qwerty    azerty
+ * 1 2 3 C   1 2 3 4   1 2 3 4
+ * 4 5 6 D   q w e r   a z e r
+ * 7 8 9 E   a s d f   q s d f
+ * A 0 B F   z x c v   w x c v
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Keymap {
+    keys: [Key; NUMBER_OF_KEYS],
+}
+
+impl Keymap {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "qwerty" => Some(Keymap {
+                keys: [
+                    Key::X, Key::Key1, Key::Key2, Key::Key3,
+                    Key::Q, Key::W, Key::E, Key::A,
+                    Key::S, Key::D, Key::Z, Key::C,
+                    Key::Key4, Key::R, Key::F, Key::V,
+                ]
+            }),
+            "azerty" => Some(Keymap {
+                keys: [
+                    Key::X, Key::Key1, Key::Key2, Key::Key3,
+                    Key::A, Key::Z, Key::E, Key::Q,
+                    Key::S, Key::D, Key::W, Key::C,
+                    Key::Key4, Key::R, Key::F, Key::V,
+                ]
+            }),
+            _ => None
+        }
+    }
+
+    // Keypad values of the keys pressed since the last window update
+    pub fn pressed_keys(&self, window: &Window) -> Vec<usize> {
+        self.keypad_values(&window.get_keys_pressed(KeyRepeat::No))
+    }
+
+    // Keypad values of the keys released since the last window update
+    pub fn released_keys(&self, window: &Window) -> Vec<usize> {
+        self.keypad_values(&window.get_keys_released())
+    }
+
+    fn keypad_values(&self, keys: &[Key]) -> Vec<usize> {
+        keys.iter().filter_map(|key| self.keys.iter().position(|mapped| mapped == key)).collect()
+    }
+}
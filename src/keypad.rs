@@ -0,0 +1,109 @@
+use minifb::{MouseButton, MouseMode, Window};
+
+use crate::chip8::{DISPLAY_HEIGHT, DISPLAY_WIDTH, PIXEL_OFF, PIXEL_ON};
+use crate::overlay::{Canvas, GLYPH_HEIGHT, GLYPH_WIDTH};
+use crate::palette::{Palette, mix};
+use crate::scheduler::{KeySource, Scheduler};
+
+// Keypad values by row, as on the COSMAC VIP
+const LAYOUT: [[usize; 4]; 4] = [
+    [0x1, 0x2, 0x3, 0xC],
+    [0x4, 0x5, 0x6, 0xD],
+    [0x7, 0x8, 0x9, 0xE],
+    [0xA, 0x0, 0xB, 0xF],
+];
+
+// In emulated pixels, the panel is as high as the display
+const CELL_SIZE: usize = DISPLAY_HEIGHT / 4;
+// Gap on the left and top of each key
+const CELL_SPACING: usize = 1;
+const KEY_SIZE: usize = CELL_SIZE - CELL_SPACING;
+const PANEL_WIDTH: usize = CELL_SIZE * 4;
+// Foreground kept in the color of a released key
+const KEY_BRIGHTNESS: f32 = 0.25;
+
+/*
+ * Hexadecimal keypad drawn on the right of the display, pressed with the mouse
+ * A key is held while the left button is down over it.
+ */
+pub struct KeypadPanel {
+    scale: usize,
+    buffer: Vec<u32>,
+    pressed: Option<usize>,
+}
+
+impl KeypadPanel {
+    pub fn new(scale: usize) -> Self {
+        KeypadPanel {
+            scale,
+            buffer: vec![0; (DISPLAY_WIDTH + PANEL_WIDTH) * scale * DISPLAY_HEIGHT * scale],
+            pressed: None,
+        }
+    }
+
+    // Width of the display and the panel, in window pixels
+    pub fn window_width(&self) -> usize {
+        (DISPLAY_WIDTH + PANEL_WIDTH) * self.scale
+    }
+
+    // Presses the key under the mouse, and releases the previous one
+    pub fn update(&mut self, window: &Window, scheduler: &mut Scheduler) {
+        let key = if window.get_mouse_down(MouseButton::Left) {
+            window.get_mouse_pos(MouseMode::Discard).and_then(|(x, y)| self.key_at(x, y))
+        } else {
+            None
+        };
+        if key != self.pressed {
+            if let Some(released) = self.pressed {
+                scheduler.key_up(KeySource::Panel, released);
+            }
+            if let Some(pressed) = key {
+                scheduler.key_down(KeySource::Panel, pressed);
+            }
+            self.pressed = key;
+        }
+    }
+
+    // Forgets the pressed key, after the keypad was released, e.g. while the menu is open
+    pub fn release(&mut self) {
+        self.pressed = None;
+    }
+
+    // The display buffer with the panel on its right
+    pub fn render(&mut self, display: &[u32], palette: &Palette) -> &[u32] {
+        let display_width = DISPLAY_WIDTH * self.scale;
+        let width = self.window_width();
+        for (row, line) in self.buffer.chunks_exact_mut(width).zip(display.chunks_exact(display_width)) {
+            row[..display_width].copy_from_slice(line);
+        }
+
+        let background = palette.color(PIXEL_OFF);
+        let foreground = palette.color(PIXEL_ON);
+        let mut canvas = Canvas::new(&mut self.buffer, width, DISPLAY_HEIGHT * self.scale, self.scale);
+        canvas.draw_rect(DISPLAY_WIDTH, 0, PANEL_WIDTH, DISPLAY_HEIGHT, background);
+        for (j, keys) in LAYOUT.iter().enumerate() {
+            for (i, key) in keys.iter().enumerate() {
+                let (fill, text) = if self.pressed == Some(*key) {
+                    (foreground, background)
+                } else {
+                    (mix(foreground, background, KEY_BRIGHTNESS), foreground)
+                };
+                let x = DISPLAY_WIDTH + i * CELL_SIZE + CELL_SPACING;
+                let y = j * CELL_SIZE + CELL_SPACING;
+                canvas.draw_rect(x, y, KEY_SIZE, KEY_SIZE, fill);
+                canvas.draw_text(&format!("{:X}", key), x + (KEY_SIZE - GLYPH_WIDTH) / 2, y + (KEY_SIZE - GLYPH_HEIGHT) / 2, text);
+            }
+        }
+        &self.buffer
+    }
+
+    fn key_at(&self, x: f32, y: f32) -> Option<usize> {
+        let cell = (CELL_SIZE * self.scale) as f32;
+        let column = ((x - (DISPLAY_WIDTH * self.scale) as f32) / cell).floor();
+        let row = (y / cell).floor();
+        if column < 0.0 || row < 0.0 {
+            return None;
+        }
+        LAYOUT.get(row as usize).and_then(|keys| keys.get(column as usize)).copied()
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Cursor, Read};
+use std::path::Path;
+
+use crate::octo;
+
+pub const ROM_EXTENSIONS: [&str; 5] = ["ch8", "sc8", "xo8", "gif", "zip"];
+
+const GIF_SIGNATURE: &[u8] = b"GIF8";
+const ZIP_SIGNATURE: &[u8] = b"PK\x03\x04";
+// Payload bits per GIF pixel, in the lowest bits of its color index
+const CARTRIDGE_BITS_PER_PIXEL: usize = 2;
+const CARTRIDGE_LENGTH_SIZE: usize = 4;
+
+/*
+ * Program and the options it was packaged with, e.g. in an Octo cartridge.
+ * The options use the command line option names, like a ROM config file.
+ */
+pub struct Rom {
+    pub program: Vec<u8>,
+    pub options: HashMap<String, String>,
+}
+
+/*
+ * Reads a ROM from
+ * - a raw binary, e.g. rom/PONG or game.ch8
+ * - an Octo cartridge GIF
+ * - a zip archive, the first ROM inside is read
+ */
+pub fn load_rom(path: &Path) -> Result<Rom, Box<dyn std::error::Error>> {
+    let data = fs::read(path).map_err(|e| format!("could not read {}: {}", path.display(), e))?;
+    parse_rom(data).map_err(|e| format!("could not load {}: {}", path.display(), e).into())
+}
+
+fn parse_rom(data: Vec<u8>) -> Result<Rom, Box<dyn std::error::Error>> {
+    if data.starts_with(GIF_SIGNATURE) {
+        parse_cartridge(&data)
+    } else if data.starts_with(ZIP_SIGNATURE) {
+        parse_rom(unzip(data)?)
+    } else {
+        Ok(Rom { program: data, options: HashMap::new() })
+    }
+}
+
+// The first file with a ROM extension, or the first file
+fn unzip(data: Vec<u8>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(data))?;
+    let names: Vec<String> = archive.file_names().map(|name| name.to_string()).collect();
+    let mut files: Vec<&String> = names.iter().filter(|name| !name.ends_with('/')).collect();
+    files.sort_by_key(|name| !has_rom_extension(name));
+    let name = files.first().ok_or("empty zip archive")?;
+
+    let mut file = archive.by_name(name)?;
+    let mut content = Vec::new();
+    file.read_to_end(&mut content)?;
+    Ok(content)
+}
+
+fn has_rom_extension(name: &str) -> bool {
+    Path::new(name).extension()
+        .map(|extension| ROM_EXTENSIONS.iter().any(|rom_extension| extension.eq_ignore_ascii_case(rom_extension)))
+        .unwrap_or(false)
+}
+
+/*
+ * Octo cartridge: a GIF whose pixels hide a JSON payload `{"program": ..., "options": {...}}`
+ * Each byte is spread over 4 pixels, 2 bits per pixel from the highest, across all the frames.
+ * The payload starts with its length, on 4 bytes, big endian.
+ */
+fn parse_cartridge(data: &[u8]) -> Result<Rom, Box<dyn std::error::Error>> {
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::Indexed);
+    let mut decoder = options.read_info(data)?;
+
+    let mut bits = Vec::new();
+    while let Some(frame) = decoder.read_next_frame()? {
+        bits.extend(frame.buffer.iter().map(|index| index & 0x3));
+    }
+    let pixels_per_byte = 8 / CARTRIDGE_BITS_PER_PIXEL;
+    let bytes: Vec<u8> = bits.chunks_exact(pixels_per_byte)
+        .map(|chunk| chunk.iter().fold(0, |byte, bits| (byte << CARTRIDGE_BITS_PER_PIXEL) | bits))
+        .collect();
+    if bytes.len() < CARTRIDGE_LENGTH_SIZE {
+        return Err("not an Octo cartridge".into());
+    }
+    let length = bytes[..CARTRIDGE_LENGTH_SIZE].iter().fold(0usize, |length, byte| (length << 8) | *byte as usize);
+    let payload = bytes.get(CARTRIDGE_LENGTH_SIZE..CARTRIDGE_LENGTH_SIZE + length)
+        .ok_or("not an Octo cartridge, truncated payload")?;
+    let payload = json::parse(&String::from_utf8_lossy(payload))
+        .map_err(|e| format!("not an Octo cartridge, {}", e))?;
+
+    let program = cartridge_program(&payload["program"])?;
+    Ok(Rom { program, options: cartridge_options(&payload["options"]) })
+}
+
+// Octo saves the program source, assembled here
+fn cartridge_program(program: &json::JsonValue) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let source = program.as_str().ok_or("the cartridge has no program")?;
+    Ok(octo::assemble(source)?)
+}
+
+// Octo options that have a command line equivalent
+fn cartridge_options(options: &json::JsonValue) -> HashMap<String, String> {
+    let mut values = HashMap::new();
+    if let Some(tickrate) = options["tickrate"].as_f64() {
+        // Instructions per 60 Hz frame
+        values.insert("frequency".to_string(), (tickrate * 60.0).to_string());
+    }
+    if let Some(color) = options["fillColor"].as_str() {
+        values.insert("fg".to_string(), color.to_string());
+    }
+    if let Some(color) = options["fillColor2"].as_str() {
+        values.insert("fg2".to_string(), color.to_string());
+    }
+    if let Some(color) = options["blendColor"].as_str() {
+        values.insert("blend-color".to_string(), color.to_string());
+    }
+    if let Some(color) = options["backgroundColor"].as_str() {
+        values.insert("bg".to_string(), color.to_string());
+    }
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::io::Write;
+
+    use super::*;
+
+    // Hides `payload` in the pixels of a GIF, like Octo
+    fn cartridge(payload: &str) -> Vec<u8> {
+        let mut bytes = (payload.len() as u32).to_be_bytes().to_vec();
+        bytes.extend_from_slice(payload.as_bytes());
+        let mut pixels: Vec<u8> = bytes.iter()
+            .flat_map(|byte| (0..4).rev().map(move |i| (byte >> (i * CARTRIDGE_BITS_PER_PIXEL)) & 0x3))
+            .collect();
+        let width = 32;
+        pixels.resize(pixels.len().div_ceil(width) * width, 0);
+
+        let mut data = Vec::new();
+        {
+            let palette = [0, 0, 0, 0x55, 0x55, 0x55, 0xAA, 0xAA, 0xAA, 0xFF, 0xFF, 0xFF];
+            let height = (pixels.len() / width) as u16;
+            let mut encoder = gif::Encoder::new(&mut data, width as u16, height, &palette).unwrap();
+            let frame = gif::Frame { width: width as u16, height, buffer: Cow::Owned(pixels), ..gif::Frame::default() };
+            encoder.write_frame(&frame).unwrap();
+        }
+        data
+    }
+
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, content) in files {
+            writer.start_file(*name, zip::write::FileOptions::default()).unwrap();
+            writer.write_all(content).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn raw_binary() {
+        let rom = parse_rom(vec![0x12, 0x00]).unwrap();
+        assert_eq!(rom.program, vec![0x12, 0x00]);
+        assert!(rom.options.is_empty());
+    }
+
+    #[test]
+    fn octo_cartridge() {
+        let payload = r##"{"program": ": main v0 := 1 jump main", "options": {"tickrate": 20, "fillColor": "#FF8000"}}"##;
+        let rom = parse_rom(cartridge(payload)).unwrap();
+        assert_eq!(rom.program, vec![0x60, 0x01, 0x12, 0x00]);
+        assert_eq!(rom.options.get("frequency").map(|value| value.as_str()), Some("1200"));
+        assert_eq!(rom.options.get("fg").map(|value| value.as_str()), Some("#FF8000"));
+    }
+
+    #[test]
+    fn cartridge_without_program() {
+        assert!(parse_rom(cartridge(r#"{"options": {}}"#)).is_err());
+    }
+
+    #[test]
+    fn zipped_rom() {
+        let data = zip(&[("README.txt", b"not a ROM"), ("games/PONG.ch8", &[0x60, 0x01, 0x12, 0x00])]);
+        assert_eq!(parse_rom(data).unwrap().program, vec![0x60, 0x01, 0x12, 0x00]);
+    }
+
+    #[test]
+    fn zipped_cartridge() {
+        let data = zip(&[("game.gif", &cartridge(r#"{"program": ": main jump main"}"#))]);
+        assert_eq!(parse_rom(data).unwrap().program, vec![0x12, 0x00]);
+    }
+
+    #[test]
+    fn zip_without_files() {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        writer.add_directory("games/", zip::write::FileOptions::default()).unwrap();
+        let data = writer.finish().unwrap().into_inner();
+        assert_eq!(parse_rom(data).err().map(|e| e.to_string()), Some("empty zip archive".to_string()));
+    }
+}
@@ -1,75 +1,185 @@
 extern crate minifb;
 extern crate clap;
-extern crate math;
 
-use std::fs;
-use minifb::{Key, Window, WindowOptions};
-use std::thread;
-use std::time::{Instant, Duration};
-use clap::{Arg, App};
-use rodio::{Sink, Source};
+use std::path::{Path, PathBuf};
+use minifb::{Key, KeyRepeat, Window, WindowOptions};
+use std::time::Instant;
+use clap::{Arg, App, ArgMatches};
 
-use crate::chip8::{Chip8, DISPLAY_WIDTH, DISPLAY_HEIGHT, NUMBER_OF_KEYS, KEY_NOT_PRESSED, KEY_PRESSED, CHIP_FREQUENCY};
+use crate::audio::{AUDIO_BACKENDS, AudioOutputs, AudioSink, WavSink, create_sink};
+use crate::buzzer::{BuzzerConfig, DEFAULT_TONE_FREQUENCY, DEFAULT_VOLUME, DEFAULT_RAMP_MILLISECONDS};
+use crate::browser::RecentRoms;
+use crate::chip8::{Chip8, Fault, KeyWaitMode, KEY_WAIT_MODES, STACK_DEPTHS, MemoryOverflow, MEMORY_OVERFLOW_MODES, START_PROGRAM_SPACE, DISPLAY_WIDTH, DISPLAY_HEIGHT, CHIP_FREQUENCY};
+use crate::config::RomConfig;
+use crate::filter::parse_filters;
+use crate::font::Font;
+use crate::gamepad::{Controllers, GamepadDevice, GamepadInput, GamepadMapping, ScriptedGamepad, DEFAULT_GAMEPAD_MAP};
+use crate::loader::load_rom;
+use crate::keymap::{Keymap, KEYMAP_NAMES};
+use crate::keypad::KeypadPanel;
+use crate::menu::{Menu, MenuAction};
+use crate::overlay::{Canvas, Overlay};
+use crate::palette::{Palette, PALETTE_COLOR_OPTIONS, PALETTE_NAMES, parse_color};
+use crate::persistence::{Persistence, PersistenceMode};
+use crate::recorder::Recorder;
+use crate::scheduler::{KeySource, Scheduler, Speed, TimingMode, NORMAL_SPEED, TIMING_MODES};
+use crate::watcher::FileWatcher;
 
+mod audio;
+mod browser;
+mod buzzer;
 mod chip8;
+mod config;
+mod filter;
+mod font;
+mod gamepad;
+mod keymap;
+mod keypad;
+mod loader;
+mod menu;
+mod octo;
+mod overlay;
+mod palette;
+mod persistence;
+mod recorder;
+mod scheduler;
+mod screenshot;
+mod watcher;
+
+const FRAME_RATE: f64 = 60.0;
+// Longest wall clock time emulated in one frame, e.g. after the window was dragged
+const MAX_FRAME_TIME: f64 = 0.25;
+// Emulated time a key pressed and released within one frame is held, long enough for ROMs that poll once per frame
+const TAP_DURATION: f64 = 1.0 / FRAME_RATE;
+// Emulated pixels per overlay font pixel
+const OVERLAY_SCALE_DIVISOR: usize = 5;
+// Instructions run between two checks of the benchmark duration
+const BENCHMARK_CHECK_STEPS: u64 = 10_000;
 
 fn get_index(i: usize, j: usize, width: usize) -> usize {
     i + j * width
 }
 
-fn get_keys(window: &Window) -> [u8; NUMBER_OF_KEYS] {
-    let mut keys = [KEY_NOT_PRESSED; NUMBER_OF_KEYS];
+// Options that can be set in a ROM config file
+const ROM_CONFIG_OPTIONS: [&str; 25] = [
+    "scale", "palette", "bg", "fg", "fg2", "blend-color", "persistence", "filter", "keymap", "gamepad",
+    "screenshot-dir", "audio", "program-start", "memory-overflow", "stack-depth", "key-wait", "font",
+    "font-address", "frequency", "timing", "speed", "tone", "volume", "attack", "release",
+];
 
-    if window.is_key_down(Key::Key1) {
-        keys[0x1] = KEY_PRESSED;
-    }
-    if window.is_key_down(Key::Key2) {
-        keys[0x2] = KEY_PRESSED;
-    }
-    if window.is_key_down(Key::Key3) {
-        keys[0x3] = KEY_PRESSED;
-    }
-    if window.is_key_down(Key::Key4) {
-        keys[0xC] = KEY_PRESSED;
-    }
-    if window.is_key_down(Key::Q) {
-        keys[0x4] = KEY_PRESSED;
+// Command line, then ROM config, then default value
+fn value_of<'a>(matches: &'a ArgMatches, config: &'a RomConfig, name: &str) -> Option<&'a str> {
+    debug_assert!(ROM_CONFIG_OPTIONS.contains(&name), "{} is not a ROM config option", name);
+    if matches.occurrences_of(name) > 0 {
+        return matches.value_of(name);
     }
-    if window.is_key_down(Key::W) {
-        keys[0x5] = KEY_PRESSED;
-    }
-    if window.is_key_down(Key::E) {
-        keys[0x6] = KEY_PRESSED;
-    }
-    if window.is_key_down(Key::R) {
-        keys[0xD] = KEY_PRESSED;
+    config.get(name).or_else(|| matches.value_of(name))
+}
+
+// Core options, applied again to the chip of each reloaded ROM
+#[derive(Debug, Clone, Copy)]
+struct ChipSettings {
+    seed: Option<u64>,
+    program_start: usize,
+    memory_overflow: MemoryOverflow,
+    font: Font,
+    font_address: usize,
+    stack_depth: usize,
+    key_wait: KeyWaitMode,
+}
+
+// Forgets the keys held by every input, once the keypad of the chip is released or the chip replaced
+fn release_inputs(scheduler: &mut Scheduler, gamepad: &mut Option<GamepadInput>, keypad: &mut Option<KeypadPanel>) {
+    scheduler.release_keys();
+    if let Some(gamepad) = gamepad {
+        gamepad.release();
     }
-    if window.is_key_down(Key::A) {
-        keys[0x7] = KEY_PRESSED;
+    if let Some(panel) = keypad {
+        panel.release();
     }
-    if window.is_key_down(Key::S) {
-        keys[0x8] = KEY_PRESSED;
+}
+
+fn new_chip(rom: &[u8], settings: &ChipSettings) -> Result<Chip8, Box<dyn std::error::Error>> {
+    let mut chip = Chip8::new(rom.to_vec())?;
+    if let Some(seed) = settings.seed {
+        chip.set_seed(seed);
     }
-    if window.is_key_down(Key::D) {
-        keys[0x9] = KEY_PRESSED;
+    if settings.program_start != START_PROGRAM_SPACE {
+        chip.set_program_start(settings.program_start)?;
     }
-    if window.is_key_down(Key::F) {
-        keys[0xE] = KEY_PRESSED;
+    chip.set_memory_overflow(settings.memory_overflow);
+    chip.set_stack_depth(settings.stack_depth);
+    chip.set_key_wait(settings.key_wait);
+    chip.set_font(settings.font, settings.font_address)?;
+    Ok(chip)
+}
+
+// Instructions executed per second of wall clock time over `seconds`, stepping the chip without scheduler or audio
+fn benchmark(rom: &[u8], settings: &ChipSettings, cache_instructions: bool, seconds: f64) -> Result<f64, Box<dyn std::error::Error>> {
+    let mut chip = new_chip(rom, settings)?;
+    chip.set_instruction_cache(cache_instructions);
+    // Timers still tick at the frame rate, for the ROMs waiting on the delay timer
+    let steps_per_tick = (CHIP_FREQUENCY / FRAME_RATE) as u64;
+    let mut executed: u64 = 0;
+    let start = Instant::now();
+    while start.elapsed().as_secs_f64() < seconds {
+        for _ in 0..BENCHMARK_CHECK_STEPS {
+            chip.step().map_err(|e| with_call_stack(e, &chip))?;
+            executed += 1;
+            if executed.is_multiple_of(steps_per_tick) {
+                chip.tick();
+            }
+        }
     }
-    if window.is_key_down(Key::Z) {
-        keys[0xA] = KEY_PRESSED;
+    Ok(executed as f64 / start.elapsed().as_secs_f64())
+}
+
+// Platform name, or number of nested calls
+fn parse_stack_depth(value: &str) -> Result<usize, String> {
+    match STACK_DEPTHS.iter().find(|(name, _)| *name == value) {
+        Some((_, depth)) => Ok(*depth),
+        None => value.parse::<usize>().map_err(|_| format!("invalid stack depth {}", value)),
     }
-    if window.is_key_down(Key::X) {
-        keys[0x0] = KEY_PRESSED;
+}
+
+// Faults of the core are reported with the calls that led to them
+fn with_call_stack(error: Box<dyn std::error::Error>, chip: &Chip8) -> Box<dyn std::error::Error> {
+    if error.downcast_ref::<Fault>().is_none() {
+        return error;
     }
-    if window.is_key_down(Key::C) {
-        keys[0xB] = KEY_PRESSED;
+    if chip.call_stack().is_empty() {
+        return error.to_string().into();
     }
-    if window.is_key_down(Key::V) {
-        keys[0xF] = KEY_PRESSED;
+    let calls: Vec<String> = chip.call_stack().iter().rev().map(|address| format!("0x{:03X}", address)).collect();
+    format!("{}, returning to {}", error, calls.join(" <- ")).into()
+}
+
+// Hexadecimal with 0x, or decimal
+fn parse_address(value: &str) -> Result<usize, String> {
+    match value.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => value.parse::<usize>(),
+    }.map_err(|_| format!("invalid address {}", value))
+}
+
+fn status_text(scheduler: &Scheduler) -> String {
+    if scheduler.is_paused() {
+        "PAUSED".to_string()
+    } else if scheduler.speed() == NORMAL_SPEED {
+        String::new()
+    } else {
+        format!("SPEED {}", scheduler.speed())
     }
+}
 
-    keys
+fn window_title(scheduler: &Scheduler) -> String {
+    if scheduler.is_paused() {
+        "chip8 - paused".to_string()
+    } else if scheduler.speed() == NORMAL_SPEED {
+        "chip8".to_string()
+    } else {
+        format!("chip8 - {}", scheduler.speed())
+    }
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -86,69 +196,557 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         .arg(Arg::with_name("rom")
             .long("rom")
             .takes_value(true)
-            .required(true)
-            .help("Rom path"))
+            .help("Rom path, opens the ROM browser if missing"))
+        .arg(Arg::with_name("rom-dir")
+            .long("rom-dir")
+            .takes_value(true)
+            .default_value("rom")
+            .help("Directory listed by the ROM browser"))
+        .arg(Arg::with_name("palette")
+            .long("palette")
+            .takes_value(true)
+            .default_value("default")
+            .possible_values(&PALETTE_NAMES)
+            .help("Color palette"))
+        .arg(Arg::with_name("fg")
+            .long("fg")
+            .takes_value(true)
+            .help("Foreground color as RRGGBB, overrides the palette"))
+        .arg(Arg::with_name("bg")
+            .long("bg")
+            .takes_value(true)
+            .help("Background color as RRGGBB, overrides the palette"))
+        .arg(Arg::with_name("fg2")
+            .long("fg2")
+            .takes_value(true)
+            .help("Second plane color as RRGGBB, overrides the palette"))
+        .arg(Arg::with_name("blend-color")
+            .long("blend-color")
+            .takes_value(true)
+            .help("Color of the pixels on both planes as RRGGBB, overrides the palette"))
+        .arg(Arg::with_name("persistence")
+            .long("persistence")
+            .takes_value(true)
+            .default_value("none")
+            .help("Anti-flicker filter: none, decay[:factor] or blend[:frames]"))
+        .arg(Arg::with_name("filter")
+            .long("filter")
+            .takes_value(true)
+            .default_value("none")
+            .help("Comma separated post-processing filters: scanlines, grid, bloom, rounded"))
+        .arg(Arg::with_name("keymap")
+            .long("keymap")
+            .takes_value(true)
+            .default_value("qwerty")
+            .possible_values(&KEYMAP_NAMES)
+            .help("Keyboard layout of the keypad"))
+        .arg(Arg::with_name("show-fps")
+            .long("show-fps")
+            .help("Show the frame rate (F2)"))
+        .arg(Arg::with_name("keypad")
+            .long("keypad")
+            .help("Show a hexadecimal keypad next to the display, pressed with the mouse"))
+        .arg(Arg::with_name("gamepad")
+            .long("gamepad")
+            .takes_value(true)
+            .default_value(DEFAULT_GAMEPAD_MAP)
+            .help("Gamepad buttons of the keypad, e.g. up=2,south=5, buttons are up, down, left, right, south, east, north, west, start, select, l1, r1, l2, r2 and stick-up, stick-down, stick-left, stick-right"))
+        .arg(Arg::with_name("gamepad-script")
+            .long("gamepad-script")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("Simulated gamepad replaying the button presses of a script, instead of the controllers"))
+        .arg(Arg::with_name("screenshot-dir")
+            .long("screenshot-dir")
+            .takes_value(true)
+            .default_value(".")
+            .help("Directory where screenshots (F12) and recordings (F10) are saved"))
+        .arg(Arg::with_name("record-video")
+            .long("record-video")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("Record gameplay from the start to a .gif or .y4m file"))
+        .arg(Arg::with_name("audio")
+            .long("audio")
+            .takes_value(true)
+            .default_value("rodio")
+            .possible_values(&AUDIO_BACKENDS)
+            .help("Audio output: sound device, none, or WAV file"))
+        .arg(Arg::with_name("audio-file")
+            .long("audio-file")
+            .takes_value(true)
+            .default_value("chip8-audio.wav")
+            .help("WAV file written by the wav audio output"))
+        .arg(Arg::with_name("audio-export")
+            .long("audio-export")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("Also write all the audio of the run to a WAV file, in emulated time"))
+        .arg(Arg::with_name("seed")
+            .long("seed")
+            .takes_value(true)
+            .help("Seed of the random number generator, for reproducible runs"))
+        .arg(Arg::with_name("program-start")
+            .long("program-start")
+            .takes_value(true)
+            .default_value("0x200")
+            .help("Address where the ROM is loaded and started, e.g. 0x600 for the ETI 660"))
+        .arg(Arg::with_name("memory-overflow")
+            .long("memory-overflow")
+            .takes_value(true)
+            .default_value("wrap")
+            .possible_values(&MEMORY_OVERFLOW_MODES)
+            .help("Memory access past 0xFFF: wrap around, or stop with an error"))
+        .arg(Arg::with_name("stack-depth")
+            .long("stack-depth")
+            .takes_value(true)
+            .default_value("schip")
+            .help("Nested calls before a stack overflow: vip (12), schip (16), or a number"))
+        .arg(Arg::with_name("key-wait")
+            .long("key-wait")
+            .takes_value(true)
+            .default_value("release")
+            .possible_values(&KEY_WAIT_MODES)
+            .help("Fx0A waits for a key to be pressed then released, or only pressed"))
+        .arg(Arg::with_name("font")
+            .long("font")
+            .takes_value(true)
+            .default_value("default")
+            .help("Font: default, vip, dream6800, eti660, or a font file"))
+        .arg(Arg::with_name("font-address")
+            .long("font-address")
+            .takes_value(true)
+            .default_value("0x050")
+            .help("Address of the font in memory"))
+        .arg(Arg::with_name("frequency")
+            .long("frequency")
+            .takes_value(true)
+            .help("Instructions per second in fixed timing, 500 by default"))
+        .arg(Arg::with_name("timing")
+            .long("timing")
+            .takes_value(true)
+            .default_value("fixed")
+            .possible_values(&TIMING_MODES)
+            .help("Instruction timing: fixed rate, or COSMAC VIP cycles"))
+        .arg(Arg::with_name("speed")
+            .long("speed")
+            .takes_value(true)
+            .default_value("1")
+            .help("Emulation speed multiplier, e.g. 0.5, 2, or unlimited"))
+        .arg(Arg::with_name("tone")
+            .long("tone")
+            .takes_value(true)
+            .default_value(DEFAULT_TONE_FREQUENCY)
+            .help("Buzzer frequency in Hz"))
+        .arg(Arg::with_name("volume")
+            .long("volume")
+            .takes_value(true)
+            .default_value(DEFAULT_VOLUME)
+            .help("Buzzer volume, from 0 to 1"))
+        .arg(Arg::with_name("attack")
+            .long("attack")
+            .takes_value(true)
+            .default_value(DEFAULT_RAMP_MILLISECONDS)
+            .help("Buzzer fade in duration in milliseconds"))
+        .arg(Arg::with_name("release")
+            .long("release")
+            .takes_value(true)
+            .default_value(DEFAULT_RAMP_MILLISECONDS)
+            .help("Buzzer fade out duration in milliseconds"))
+        .arg(Arg::with_name("watch")
+            .long("watch")
+            .help("Reload the ROM when the file changes, keeping the settings and the window"))
+        .arg(Arg::with_name("headless")
+            .long("headless")
+            .takes_value(true)
+            .value_name("FRAMES")
+            .help("Run FRAMES frames without a window, then save a screenshot"))
+        .arg(Arg::with_name("benchmark")
+            .long("benchmark")
+            .takes_value(true)
+            .value_name("SECONDS")
+            .help("Print the instructions per second of the core, without then with the instruction cache, running SECONDS each"))
         .get_matches();
 
-    let scale: usize = matches.value_of("scale").ok_or("Invalid scale")?
-        .parse::<usize>().unwrap();
-    let rom_path = matches.value_of("rom").ok_or("No ROM")?;
+    if matches.is_present("headless") && !matches.is_present("rom") {
+        return Err("--headless needs a --rom".into());
+    }
+    if matches.is_present("benchmark") && !matches.is_present("rom") {
+        return Err("--benchmark needs a --rom".into());
+    }
+
+    let mut recent = RecentRoms::load();
+    let mut rom_path = matches.value_of("rom").map(PathBuf::from);
+    loop {
+        let path = match rom_path.take() {
+            Some(path) => path,
+            None => {
+                let scale = matches.value_of("scale").ok_or("Invalid scale")?.parse::<usize>()?;
+                let palette = Palette::from_name(matches.value_of("palette").ok_or("No palette")?)
+                    .ok_or("Invalid palette")?;
+                let rom_dir = Path::new(matches.value_of("rom-dir").ok_or("No ROM directory")?);
+                match browser::browse(rom_dir, recent.roms(), scale, scale / OVERLAY_SCALE_DIVISOR, &palette)? {
+                    Some(path) => path,
+                    None => return Ok(()),
+                }
+            }
+        };
+        // Only the ROMs played in a window
+        if !matches.is_present("headless") && !matches.is_present("benchmark") {
+            recent.add(&path);
+            if let Err(e) = recent.save() {
+                eprintln!("Could not save recently played ROMs: {}", e);
+            }
+        }
+
+        match run(&matches, path.to_str().ok_or("Invalid ROM path")?)? {
+            Exit::Quit => return Ok(()),
+            Exit::Browse => continue,
+        }
+    }
+}
+
+enum Exit {
+    Quit,
+    // Back to the ROM browser
+    Browse,
+}
+
+fn run(matches: &ArgMatches, rom_path: &str) -> Result<Exit, Box<dyn std::error::Error>> {
+    let rom = load_rom(Path::new(rom_path))?;
+    let mut config = RomConfig::load(rom_path, &ROM_CONFIG_OPTIONS)?;
+    config.add_defaults(rom.options);
+    let mut rom = rom.program;
+
+    let scale: usize = value_of(matches, &config, "scale").ok_or("Invalid scale")?
+        .parse::<usize>()?;
+    let mut palette_name = value_of(matches, &config, "palette").ok_or("No palette")?;
+    let mut palette = Palette::from_name(palette_name).ok_or("Invalid palette")?;
+    for (pixel, option) in PALETTE_COLOR_OPTIONS.iter().enumerate() {
+        if let Some(color) = value_of(matches, &config, option) {
+            palette = palette.with_color(pixel as u8, parse_color(color)?);
+        }
+    }
+    let mut persistence = Persistence::new(
+        PersistenceMode::parse(value_of(matches, &config, "persistence").ok_or("No persistence")?)?);
+    let filters = parse_filters(value_of(matches, &config, "filter").ok_or("No filter")?)?;
+    let mut keymap_name = value_of(matches, &config, "keymap").ok_or("No keymap")?;
+    let mut keymap = Keymap::from_name(keymap_name).ok_or("Invalid keymap")?;
+    let screenshot_dir = Path::new(value_of(matches, &config, "screenshot-dir").ok_or("No screenshot directory")?);
+    let gamepad_mapping = GamepadMapping::parse(value_of(matches, &config, "gamepad").ok_or("No gamepad mapping")?)?;
 
     let width: usize = DISPLAY_WIDTH * scale;
     let height: usize = DISPLAY_HEIGHT * scale;
 
-    let rom = fs::read(rom_path)?;
-    let mut chip = Chip8::new(rom);
+    let mut settings = ChipSettings {
+        seed: match matches.value_of("seed") {
+            Some(seed) => Some(seed.parse::<u64>()?),
+            None => None,
+        },
+        program_start: parse_address(value_of(matches, &config, "program-start").ok_or("No program start")?)?,
+        memory_overflow: MemoryOverflow::parse(value_of(matches, &config, "memory-overflow").ok_or("No memory overflow")?)?,
+        font: Font::parse(value_of(matches, &config, "font").ok_or("No font")?)?,
+        font_address: parse_address(value_of(matches, &config, "font-address").ok_or("No font address")?)?,
+        stack_depth: parse_stack_depth(value_of(matches, &config, "stack-depth").ok_or("No stack depth")?)?,
+        key_wait: KeyWaitMode::parse(value_of(matches, &config, "key-wait").ok_or("No key wait")?)?,
+    };
+    let mut chip = new_chip(&rom, &settings).map_err(|e| format!("could not load {}: {}", rom_path, e))?;
+    if let Some(seconds) = matches.value_of("benchmark") {
+        let seconds = seconds.parse::<f64>()?;
+        let decoded = benchmark(&rom, &settings, false, seconds)?;
+        println!("Without instruction cache: {:.0} instructions per second", decoded);
+        let cached = benchmark(&rom, &settings, true, seconds)?;
+        println!("With instruction cache: {:.0} instructions per second ({:.2}x)", cached, cached / decoded);
+        return Ok(Exit::Quit);
+    }
+    // Controllers only with a window, a script also runs headless
+    let gamepad_device: Option<Box<dyn GamepadDevice>> = match matches.value_of("gamepad-script") {
+        Some(path) => Some(Box::new(ScriptedGamepad::load(path)?)),
+        None if matches.is_present("headless") => None,
+        None => match Controllers::new() {
+            Ok(controllers) => Some(Box::new(controllers)),
+            Err(e) => {
+                eprintln!("{}", e);
+                None
+            }
+        },
+    };
+    let mut gamepad = gamepad_device.map(|device| GamepadInput::new(device, gamepad_mapping));
+    let buzzer = BuzzerConfig {
+        frequency: value_of(matches, &config, "tone").ok_or("No tone")?.parse::<f32>()?,
+        volume: value_of(matches, &config, "volume").ok_or("No volume")?.parse::<f32>()?.clamp(0.0, 1.0),
+        attack: value_of(matches, &config, "attack").ok_or("No attack")?.parse::<f32>()? / 1000.0,
+        release: value_of(matches, &config, "release").ok_or("No release")?.parse::<f32>()? / 1000.0,
+    };
+    // No sound by default without a window, e.g. on CI machines without a sound card
+    let audio_backend = match value_of(matches, &config, "audio") {
+        _ if matches.is_present("headless") && matches.occurrences_of("audio") == 0 && config.get("audio").is_none() => "null",
+        backend => backend.ok_or("No audio output")?,
+    };
+    let mut audio_sinks = vec![create_sink(audio_backend,
+                                           Path::new(matches.value_of("audio-file").ok_or("No audio file")?),
+                                           buzzer)?];
+    if let Some(path) = matches.value_of("audio-export") {
+        audio_sinks.push(Box::new(WavSink::create(Path::new(path), buzzer)?));
+    }
+    let mut audio = AudioOutputs::new(audio_sinks);
+    let frequency = match value_of(matches, &config, "frequency") {
+        Some(frequency) => frequency.parse::<f64>()?,
+        None => CHIP_FREQUENCY,
+    };
+    let timing = TimingMode::parse(value_of(matches, &config, "timing").ok_or("No timing")?)?;
+    let mut scheduler = Scheduler::new(frequency, timing);
+    scheduler.set_speed(Speed::parse(value_of(matches, &config, "speed").ok_or("No speed")?)?);
+    let mut recorder = match matches.value_of("record-video") {
+        Some(path) => Some(Recorder::create(Path::new(path), DISPLAY_WIDTH, DISPLAY_HEIGHT, scale)?),
+        None => None,
+    };
+
+    if let Some(frames) = matches.value_of("headless") {
+        let frames = frames.parse::<usize>()?;
+        for _ in 0..frames {
+            if let Some(gamepad) = &mut gamepad {
+                gamepad.update(&mut scheduler, 1.0 / FRAME_RATE);
+            }
+            scheduler.run(&mut chip, 1.0 / FRAME_RATE, &mut audio).map_err(|e| with_call_stack(e, &chip))?;
+            if let Some(recorder) = &mut recorder {
+                recorder.record(persistence.apply(&chip.get_display(), &palette))?;
+            }
+        }
+        audio.finish()?;
+        if let Some(recorder) = recorder {
+            recorder.finish()?;
+        }
+        let path = screenshot::capture(&chip, &palette, scale, screenshot_dir)?;
+        println!("Saved screenshot to {}", path.display());
+        return Ok(Exit::Quit);
+    }
 
     {
         let mut buffer: Vec<u32> = vec![0; width * height];
         buffer[10] = 0xFFFF_FFFF;
+        let mut keypad = if matches.is_present("keypad") {
+            Some(KeypadPanel::new(scale))
+        } else {
+            None
+        };
+        let window_width = keypad.as_ref().map(|panel| panel.window_width()).unwrap_or(width);
 
         let mut window = Window::new(
             "chip8",
-            width,
+            window_width,
             height,
             WindowOptions::default(), )
             .unwrap_or_else(|e| {
                 panic!("{}", e);
             });
-        let device = rodio::default_output_device().unwrap();
-        let sink = Sink::new(&device);
-        let source = rodio::source::SineWave::new(440).repeat_infinite();
-        sink.append(source);
-        sink.pause();
-
         // Limit to max ~60 fps update rate
         window.limit_update_rate(Some(std::time::Duration::from_micros(16600)));
+        window.set_title(&window_title(&scheduler));
         let mut last_time = Instant::now();
 
-        while window.is_open() && !window.is_key_down(Key::Escape) {
-            let keys = get_keys(&window);
-            chip.set_keypad(keys);
+        let mut overlay = Overlay::new(matches.is_present("show-fps"));
+        let mut menu: Option<Menu> = None;
+        let mut exit = Exit::Quit;
+        let mut watcher = if matches.is_present("watch") {
+            Some(FileWatcher::new(Path::new(rom_path)))
+        } else {
+            None
+        };
+
+        'running: while window.is_open() {
+            if window.is_key_pressed(Key::Escape, KeyRepeat::No) {
+                if menu.is_none() {
+                    break;
+                }
+                menu = None;
+            }
+            if window.is_key_pressed(Key::F1, KeyRepeat::No) {
+                menu = match menu {
+                    Some(_) => None,
+                    None => {
+                        // Keys released while the menu is open are not seen
+                        chip.release_keys();
+                        release_inputs(&mut scheduler, &mut gamepad, &mut keypad);
+                        Some(Menu::new(palette_name, keymap_name, settings.key_wait, settings.memory_overflow, settings.stack_depth))
+                    }
+                };
+            }
+            if window.is_key_pressed(Key::F2, KeyRepeat::No) {
+                overlay.toggle_fps();
+            }
+
+            if let Some(active) = &mut menu {
+                let actions: Vec<MenuAction> = window.get_keys_pressed(KeyRepeat::Yes).into_iter()
+                    .filter_map(|key| active.handle_key(key))
+                    .collect();
+                for action in actions {
+                    match action {
+                        MenuAction::Resume => menu = None,
+                        MenuAction::Reset => {
+                            match new_chip(&rom, &settings) {
+                                Ok(reset) => {
+                                    chip = reset;
+                                    release_inputs(&mut scheduler, &mut gamepad, &mut keypad);
+                                    overlay.notify("Reset".to_string());
+                                }
+                                Err(e) => overlay.notify(format!("Could not reset: {}", e)),
+                            }
+                            menu = None;
+                        }
+                        MenuAction::SetKeyWait(mode) => {
+                            settings.key_wait = mode;
+                            overlay.notify("Quirks are applied on reset".to_string());
+                        }
+                        MenuAction::SetMemoryOverflow(mode) => {
+                            settings.memory_overflow = mode;
+                            overlay.notify("Quirks are applied on reset".to_string());
+                        }
+                        MenuAction::SetStackDepth(depth) => {
+                            settings.stack_depth = depth;
+                            overlay.notify("Quirks are applied on reset".to_string());
+                        }
+                        MenuAction::SetPalette(name) => {
+                            palette_name = name;
+                            palette = Palette::from_name(name).ok_or("Invalid palette")?;
+                        }
+                        MenuAction::SetKeymap(name) => {
+                            keymap_name = name;
+                            keymap = Keymap::from_name(name).ok_or("Invalid keymap")?;
+                        }
+                        MenuAction::LoadRom => {
+                            exit = Exit::Browse;
+                            break 'running;
+                        }
+                        MenuAction::Quit => break 'running,
+                    }
+                }
+            }
+
+            if let Some(active) = &mut watcher {
+                if active.changed() {
+                    match load_rom(Path::new(rom_path)).and_then(|reloaded| Ok((new_chip(&reloaded.program, &settings)?, reloaded.program))) {
+                        Ok((reloaded, program)) => {
+                            chip = reloaded;
+                            rom = program;
+                            // The keypad of the new chip is released, like when opening the menu
+                            release_inputs(&mut scheduler, &mut gamepad, &mut keypad);
+                            overlay.notify(format!("Reloaded {} ({} bytes)", rom_path, rom.len()));
+                        }
+                        Err(e) => overlay.notify(format!("Could not reload {}: {}", rom_path, e)),
+                    }
+                }
+            }
+
+            if menu.is_none() {
+                let pressed = keymap.pressed_keys(&window);
+                let released = keymap.released_keys(&window);
+                for key in &pressed {
+                    scheduler.key_down(KeySource::Keyboard, *key);
+                    if released.contains(key) {
+                        scheduler.queue_key(KeySource::Keyboard, *key, false, TAP_DURATION);
+                    }
+                }
+                for key in released.iter().filter(|key| !pressed.contains(key)) {
+                    scheduler.key_up(KeySource::Keyboard, *key);
+                }
+                if let Some(gamepad) = &mut gamepad {
+                    gamepad.update(&mut scheduler, 1.0 / FRAME_RATE);
+                }
+                if let Some(panel) = &mut keypad {
+                    panel.update(&window, &mut scheduler);
+                }
+            }
+
+            // The menu handles its own keys
+            if menu.is_none() && window.is_key_pressed(Key::F5, KeyRepeat::No) {
+                match new_chip(&rom, &settings) {
+                    Ok(reset) => {
+                        chip = reset;
+                        release_inputs(&mut scheduler, &mut gamepad, &mut keypad);
+                        overlay.notify("Reset".to_string());
+                    }
+                    Err(e) => overlay.notify(format!("Could not reset: {}", e)),
+                }
+            }
+            if menu.is_none() && window.is_key_pressed(Key::F6, KeyRepeat::No) {
+                chip.warm_reset();
+                overlay.notify("Warm reset, memory kept".to_string());
+            }
+            if window.is_key_pressed(Key::F12, KeyRepeat::No) {
+                match screenshot::capture(&chip, &palette, scale, screenshot_dir) {
+                    Ok(path) => overlay.notify(format!("Saved screenshot to {}", path.display())),
+                    Err(e) => overlay.notify(format!("Could not save screenshot: {}", e)),
+                }
+            }
+            if window.is_key_pressed(Key::F10, KeyRepeat::No) {
+                match recorder.take() {
+                    Some(active) => match active.finish() {
+                        Ok(()) => overlay.notify("Stopped recording".to_string()),
+                        Err(e) => overlay.notify(format!("Could not save recording: {}", e)),
+                    },
+                    None => {
+                        let path = screenshot::timestamped_path(screenshot_dir, "recording", "gif");
+                        match Recorder::create(&path, DISPLAY_WIDTH, DISPLAY_HEIGHT, scale) {
+                            Ok(created) => {
+                                overlay.notify(format!("Recording to {}", path.display()));
+                                recorder = Some(created);
+                            }
+                            Err(e) => overlay.notify(format!("Could not start recording: {}", e)),
+                        }
+                    }
+                }
+            }
 
-            chip.tick();
-            if chip.is_playing_sound() {
-                sink.play();
-            } else {
-                sink.pause();
+            let speed = scheduler.speed();
+            let paused = scheduler.is_paused();
+            if menu.is_none() {
+                if window.is_key_pressed(Key::P, KeyRepeat::No) {
+                    scheduler.toggle_pause();
+                }
+                if window.is_key_pressed(Key::Equal, KeyRepeat::No) {
+                    scheduler.change_speed(1);
+                }
+                if window.is_key_pressed(Key::Minus, KeyRepeat::No) {
+                    scheduler.change_speed(-1);
+                }
+                if window.is_key_pressed(Key::Backspace, KeyRepeat::No) {
+                    scheduler.set_speed(NORMAL_SPEED);
+                }
+            }
+            if scheduler.speed() != speed || scheduler.is_paused() != paused {
+                window.set_title(&window_title(&scheduler));
             }
 
             {
                 let new_time = Instant::now();
-                let elaspsed = new_time.duration_since(last_time);
-                let num_steps = math::round::floor(elaspsed.as_micros() as f64 / 1_000_000.0 * CHIP_FREQUENCY, 0) as u128;
-                for _ in 0..num_steps {
-                    chip.step()?;
+                let elapsed = new_time.duration_since(last_time).as_secs_f64();
+                if menu.is_none() {
+                    if scheduler.is_paused() && window.is_key_pressed(Key::N, KeyRepeat::Yes) {
+                        scheduler.run(&mut chip, 1.0 / FRAME_RATE, &mut audio).map_err(|e| with_call_stack(e, &chip))?;
+                    }
+                    scheduler.run_frame(&mut chip, elapsed.min(MAX_FRAME_TIME), &mut audio).map_err(|e| with_call_stack(e, &chip))?;
                 }
-                last_time = Instant::now();
+                // The core is not running, the next instruction restores the sound
+                if menu.is_some() || scheduler.is_paused() {
+                    audio.update(false, chip.get_audio_pattern());
+                }
+                last_time = new_time;
             }
 
             let display = chip.get_display();
+            let frame = persistence.apply(&display, &palette);
+            if let (Some(active), false) = (&mut recorder, scheduler.is_paused() || menu.is_some()) {
+                if let Err(e) = active.record(frame) {
+                    overlay.notify(format!("Stopped recording: {}", e));
+                    recorder = None;
+                }
+            }
 
             for i in 0..DISPLAY_WIDTH {
                 for j in 0..DISPLAY_HEIGHT {
                     let display_index = get_index(i, j, DISPLAY_WIDTH);
-                    let pixel_value = display[display_index];
+                    let pixel_value = frame[display_index];
                     for di in 0..scale {
                         for dj in 0..scale {
                             let buffer_index = get_index(i * scale + di, j * scale + dj, width);
@@ -158,11 +756,32 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 }
             }
 
+            for filter in &filters {
+                filter.apply(&mut buffer, width, height, scale);
+            }
+
+            {
+                let mut canvas = Canvas::new(&mut buffer, width, height, scale / OVERLAY_SCALE_DIVISOR);
+                overlay.frame_rendered();
+                overlay.draw(&mut canvas, &status_text(&scheduler));
+                if let Some(active) = &menu {
+                    active.draw(&mut canvas);
+                }
+            }
+
+            let window_buffer = match &mut keypad {
+                Some(panel) => panel.render(&buffer, &palette),
+                None => &buffer,
+            };
             window
-                .update_with_buffer(&buffer, width, height)
+                .update_with_buffer(window_buffer, window_width, height)
                 .unwrap();
-            thread::sleep(Duration::from_micros(16600));
         }
+
+        audio.finish()?;
+        if let Some(recorder) = recorder {
+            recorder.finish()?;
+        }
+        Ok(exit)
     }
-    Ok(())
 }
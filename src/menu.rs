@@ -0,0 +1,162 @@
+use minifb::Key;
+
+use crate::chip8::{KeyWaitMode, MemoryOverflow, KEY_WAIT_MODES, MEMORY_OVERFLOW_MODES, STACK_DEPTHS};
+use crate::keymap::KEYMAP_NAMES;
+use crate::overlay::{Canvas, text_height, text_width};
+use crate::palette::PALETTE_NAMES;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum MenuItem {
+    Resume,
+    Reset,
+    KeyWait,
+    MemoryOverflow,
+    StackDepth,
+    Palette,
+    Keymap,
+    LoadRom,
+    Quit,
+}
+
+const MENU_ITEMS: [MenuItem; 9] = [
+    MenuItem::Resume,
+    MenuItem::Reset,
+    MenuItem::KeyWait,
+    MenuItem::MemoryOverflow,
+    MenuItem::StackDepth,
+    MenuItem::Palette,
+    MenuItem::Keymap,
+    MenuItem::LoadRom,
+    MenuItem::Quit,
+];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MenuAction {
+    Resume,
+    Reset,
+    // Quirks, applied by the next reset
+    SetKeyWait(KeyWaitMode),
+    SetMemoryOverflow(MemoryOverflow),
+    SetStackDepth(usize),
+    SetPalette(&'static str),
+    SetKeymap(&'static str),
+    // Back to the ROM browser
+    LoadRom,
+    Quit,
+}
+
+/*
+ * Pause menu
+ * Up / Down    => select an item
+ * Left / Right => change the value of the item
+ * Enter        => activate the item
+ */
+pub struct Menu {
+    selected: usize,
+    key_wait: usize,
+    memory_overflow: usize,
+    // Index in STACK_DEPTHS, or the depth given on the command line
+    stack_depth: Result<usize, usize>,
+    palette: usize,
+    keymap: usize,
+}
+
+impl Menu {
+    pub fn new(palette: &str, keymap: &str, key_wait: KeyWaitMode, memory_overflow: MemoryOverflow, stack_depth: usize) -> Self {
+        Menu {
+            selected: 0,
+            key_wait: KEY_WAIT_MODES.iter().position(|name| KeyWaitMode::parse(name) == Ok(key_wait)).unwrap_or(0),
+            memory_overflow: MEMORY_OVERFLOW_MODES.iter().position(|name| MemoryOverflow::parse(name) == Ok(memory_overflow)).unwrap_or(0),
+            stack_depth: STACK_DEPTHS.iter().position(|(_, depth)| *depth == stack_depth).ok_or(stack_depth),
+            palette: PALETTE_NAMES.iter().position(|name| *name == palette).unwrap_or(0),
+            keymap: KEYMAP_NAMES.iter().position(|name| *name == keymap).unwrap_or(0),
+        }
+    }
+
+    pub fn handle_key(&mut self, key: Key) -> Option<MenuAction> {
+        let item = MENU_ITEMS[self.selected];
+        match key {
+            Key::Up => {
+                self.selected = (self.selected + MENU_ITEMS.len() - 1) % MENU_ITEMS.len();
+                None
+            }
+            Key::Down => {
+                self.selected = (self.selected + 1) % MENU_ITEMS.len();
+                None
+            }
+            Key::Left | Key::Right => {
+                let forward = key == Key::Right;
+                match item {
+                    MenuItem::KeyWait => {
+                        self.key_wait = cycle(self.key_wait, KEY_WAIT_MODES.len(), forward);
+                        KeyWaitMode::parse(KEY_WAIT_MODES[self.key_wait]).ok().map(MenuAction::SetKeyWait)
+                    }
+                    MenuItem::MemoryOverflow => {
+                        self.memory_overflow = cycle(self.memory_overflow, MEMORY_OVERFLOW_MODES.len(), forward);
+                        MemoryOverflow::parse(MEMORY_OVERFLOW_MODES[self.memory_overflow]).ok().map(MenuAction::SetMemoryOverflow)
+                    }
+                    MenuItem::StackDepth => {
+                        let index = match self.stack_depth {
+                            Ok(index) => cycle(index, STACK_DEPTHS.len(), forward),
+                            Err(_) => 0,
+                        };
+                        self.stack_depth = Ok(index);
+                        Some(MenuAction::SetStackDepth(STACK_DEPTHS[index].1))
+                    }
+                    MenuItem::Palette => {
+                        self.palette = cycle(self.palette, PALETTE_NAMES.len(), forward);
+                        Some(MenuAction::SetPalette(PALETTE_NAMES[self.palette]))
+                    }
+                    MenuItem::Keymap => {
+                        self.keymap = cycle(self.keymap, KEYMAP_NAMES.len(), forward);
+                        Some(MenuAction::SetKeymap(KEYMAP_NAMES[self.keymap]))
+                    }
+                    _ => None
+                }
+            }
+            Key::Enter => match item {
+                MenuItem::Resume => Some(MenuAction::Resume),
+                MenuItem::Reset => Some(MenuAction::Reset),
+                MenuItem::LoadRom => Some(MenuAction::LoadRom),
+                MenuItem::Quit => Some(MenuAction::Quit),
+                MenuItem::KeyWait | MenuItem::MemoryOverflow | MenuItem::StackDepth
+                | MenuItem::Palette | MenuItem::Keymap => self.handle_key(Key::Right),
+            },
+            _ => None
+        }
+    }
+
+    pub fn draw(&self, canvas: &mut Canvas) {
+        let mut lines = vec!["PAUSED".to_string(), String::new()];
+        for item in MENU_ITEMS.iter() {
+            lines.push(match item {
+                MenuItem::Resume => "Resume".to_string(),
+                MenuItem::Reset => "Reset".to_string(),
+                MenuItem::KeyWait => format!("Key wait: < {} >", KEY_WAIT_MODES[self.key_wait]),
+                MenuItem::MemoryOverflow => format!("Memory overflow: < {} >", MEMORY_OVERFLOW_MODES[self.memory_overflow]),
+                MenuItem::StackDepth => match self.stack_depth {
+                    Ok(index) => format!("Stack depth: < {} >", STACK_DEPTHS[index].0),
+                    Err(depth) => format!("Stack depth: < {} >", depth),
+                },
+                MenuItem::Palette => format!("Palette: < {} >", PALETTE_NAMES[self.palette]),
+                MenuItem::Keymap => format!("Keymap: < {} >", KEYMAP_NAMES[self.keymap]),
+                MenuItem::LoadRom => "Load ROM".to_string(),
+                MenuItem::Quit => "Quit".to_string(),
+            });
+        }
+        let lines: Vec<&str> = lines.iter().map(|line| line.as_str()).collect();
+
+        let width = lines.iter().map(|line| text_width(line)).max().unwrap_or(0);
+        let x = canvas.width_in_font_pixels().saturating_sub(width) / 2;
+        let y = canvas.height_in_font_pixels().saturating_sub(text_height(lines.len())) / 2;
+        canvas.draw_lines(&lines, x, y, Some(self.selected + 2));
+    }
+}
+
+fn cycle(index: usize, length: usize, forward: bool) -> usize {
+    if forward {
+        (index + 1) % length
+    } else {
+        (index + length - 1) % length
+    }
+}
@@ -0,0 +1,864 @@
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+
+use crate::chip8::START_PROGRAM_SPACE;
+
+const ADDRESS_SPACE: usize = 0x10000;
+// Macros using macros deeper than this are assumed to expand forever, e.g. a macro using itself
+const MAX_MACRO_DEPTH: usize = 64;
+
+#[derive(Debug, Clone)]
+struct Token {
+    text: String,
+    line: usize,
+    // Number of nested macros the token was expanded from
+    depth: usize,
+}
+
+// Value of a label used before it is defined, written once the label is known
+#[derive(Debug, Clone, Copy)]
+enum Fixup {
+    // Lowest 12 bits of the instruction at the address
+    Address,
+    // 16 bits, e.g. after `i := long`
+    Long,
+    // Highest nibble given, lowest nibble from bits 8 to 11 of the label
+    UnpackHigh(u8),
+    HighByte,
+    LowByte,
+    // Checked to fit in a byte
+    Byte,
+}
+
+enum Value {
+    Known(i64),
+    Forward(String),
+}
+
+// Instruction that skips the next one when the condition is false, and the one skipping when it is true
+struct Condition {
+    setup: Vec<u16>,
+    skip_if_false: u16,
+    skip_if_true: u16,
+}
+
+struct Loop {
+    start: usize,
+    breaks: Vec<usize>,
+}
+
+/*
+ * Assembler for Octo, the CHIP-8 assembly language of Octo cartridges
+ * https://github.com/JohnEarnest/Octo/blob/gh-pages/docs/Manual.md
+ * Supports labels, constants, aliases, macros, calc expressions, conditionals and loops.
+ * :stringmode is not supported.
+ */
+struct Assembler {
+    tokens: VecDeque<Token>,
+    line: usize,
+    memory: Vec<u8>,
+    here: usize,
+    end: usize,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    macros: HashMap<String, (Vec<String>, Vec<Token>)>,
+    fixups: Vec<(usize, String, Fixup, usize)>,
+    // Addresses of the jumps to patch at `else` and `end`
+    branches: Vec<usize>,
+    loops: Vec<Loop>,
+}
+
+// Program bytes starting at 0x200
+pub fn assemble(source: &str) -> Result<Vec<u8>, String> {
+    let mut assembler = Assembler {
+        tokens: tokenize(source),
+        line: 1,
+        memory: vec![0; ADDRESS_SPACE],
+        here: START_PROGRAM_SPACE,
+        end: START_PROGRAM_SPACE,
+        constants: HashMap::new(),
+        aliases: HashMap::new(),
+        macros: HashMap::new(),
+        fixups: Vec::new(),
+        branches: Vec::new(),
+        loops: Vec::new(),
+    };
+    assembler.run().map_err(|e| format!("line {}: {}", assembler.line, e))?;
+    Ok(assembler.memory[START_PROGRAM_SPACE..assembler.end].to_vec())
+}
+
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+    for (number, line) in source.lines().enumerate() {
+        let mut rest = line;
+        loop {
+            rest = rest.trim_start();
+            if rest.is_empty() || rest.starts_with('#') {
+                break;
+            }
+            let length = if let Some(string) = rest.strip_prefix('"') {
+                string.find('"').map(|end| end + 2).unwrap_or(rest.len())
+            } else {
+                rest.find(char::is_whitespace).unwrap_or(rest.len())
+            };
+            tokens.push_back(Token { text: rest[..length].to_string(), line: number + 1, depth: 0 });
+            rest = &rest[length..];
+        }
+    }
+    tokens
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")) {
+        i64::from_str_radix(binary, 2).ok()?
+    } else {
+        digits.parse::<i64>().ok()?
+    };
+    Some(if negative { -value } else { value })
+}
+
+impl Assembler {
+    fn run(&mut self) -> Result<(), String> {
+        while let Some(token) = self.next_token() {
+            self.statement(&token)?;
+        }
+        if !self.branches.is_empty() {
+            return Err("`begin` without `end`".to_string());
+        }
+        if !self.loops.is_empty() {
+            return Err("`loop` without `again`".to_string());
+        }
+
+        for (address, name, fixup, line) in std::mem::take(&mut self.fixups) {
+            self.line = line;
+            let value = *self.constants.get(&name).ok_or(format!("undefined name {}", name))? as i64;
+            match fixup {
+                Fixup::Address => {
+                    let value = check(value, 0xFFF)?;
+                    self.memory[address] = (self.memory[address] & 0xF0) | (value >> 8) as u8;
+                    self.memory[address + 1] = value as u8;
+                }
+                Fixup::Long => {
+                    let value = check(value, 0xFFFF)?;
+                    self.memory[address] = (value >> 8) as u8;
+                    self.memory[address + 1] = value as u8;
+                }
+                Fixup::UnpackHigh(nibble) => {
+                    let value = check(value, 0xFFF)?;
+                    self.memory[address] = (nibble << 4) | (value >> 8) as u8;
+                }
+                Fixup::HighByte => self.memory[address] = (check(value, 0xFFFF)? >> 8) as u8,
+                Fixup::LowByte => self.memory[address] = check(value, 0xFFFF)? as u8,
+                Fixup::Byte => self.memory[address] = check_byte(value)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, token: &Token) -> Result<(), String> {
+        let text = token.text.as_str();
+        if let Some(x) = self.register(text) {
+            return self.register_statement(x);
+        }
+        match text {
+            ":" => {
+                let name = self.expect_token()?;
+                self.define(name, self.here as f64)
+            }
+            ":const" => {
+                let name = self.expect_token()?;
+                let value = self.known_value()?;
+                self.define(name, value as f64)
+            }
+            ":calc" => {
+                let name = self.expect_token()?;
+                let value = self.calc()?;
+                self.define(name, value)
+            }
+            ":alias" => {
+                let name = self.expect_token()?;
+                let register = self.expect_token()?;
+                let x = match self.register(&register) {
+                    Some(x) => x,
+                    None => {
+                        self.tokens.push_front(Token { text: register, line: self.line, depth: 0 });
+                        check(self.known_value()?, 0xF)? as u8
+                    }
+                };
+                self.aliases.insert(name, x);
+                Ok(())
+            }
+            ":org" => {
+                let address = check(self.known_value()?, 0xFFFF)? as usize;
+                if address < START_PROGRAM_SPACE {
+                    return Err(format!(":org 0x{:X} is below the program start 0x{:X}", address, START_PROGRAM_SPACE));
+                }
+                self.here = address;
+                Ok(())
+            }
+            ":byte" => {
+                match self.value()? {
+                    Value::Known(value) => {
+                        let value = check_byte(value)?;
+                        self.emit_byte(value)
+                    }
+                    Value::Forward(name) => {
+                        self.fixups.push((self.here, name, Fixup::Byte, self.line));
+                        self.emit_byte(0)
+                    }
+                }
+            }
+            ":pointer" => self.emit_address(0x0000, Fixup::Long),
+            ":call" => self.emit_address(0x2000, Fixup::Address),
+            ":next" => {
+                let name = self.expect_token()?;
+                self.define(name, (self.here + 1) as f64)
+            }
+            ":unpack" => {
+                let high = self.expect_token()?;
+                if high == "long" {
+                    self.emit_unpack(None)
+                } else {
+                    self.tokens.push_front(Token { text: high, line: self.line, depth: 0 });
+                    let nibble = check(self.known_value()?, 0xF)? as u8;
+                    self.emit_unpack(Some(nibble))
+                }
+            }
+            ":macro" => self.define_macro(),
+            ":assert" => {
+                let mut message = "assertion failed".to_string();
+                if self.tokens.front().map(|token| token.text.starts_with('"')).unwrap_or(false) {
+                    message = self.expect_token()?.trim_matches('"').to_string();
+                }
+                if self.calc()? == 0.0 {
+                    return Err(message);
+                }
+                Ok(())
+            }
+            ":breakpoint" => self.expect_token().map(|_| ()),
+            ":monitor" => {
+                self.expect_token()?;
+                self.expect_token().map(|_| ())
+            }
+            ":stringmode" => Err(":stringmode is not supported".to_string()),
+            "return" | ";" => self.emit(0x00EE),
+            "clear" => self.emit(0x00E0),
+            "hires" => self.emit(0x00FF),
+            "lores" => self.emit(0x00FE),
+            "exit" => self.emit(0x00FD),
+            "scroll-left" => self.emit(0x00FC),
+            "scroll-right" => self.emit(0x00FB),
+            "scroll-down" => {
+                let n = check(self.known_value()?, 0xF)? as u16;
+                self.emit(0x00C0 | n)
+            }
+            "scroll-up" => {
+                let n = check(self.known_value()?, 0xF)? as u16;
+                self.emit(0x00D0 | n)
+            }
+            "audio" => self.emit(0xF002),
+            "plane" => {
+                let n = check(self.known_value()?, 0xF)? as u16;
+                self.emit(0xF001 | n << 8)
+            }
+            "bcd" => self.register_instruction(0xF033),
+            "saveflags" => self.register_instruction(0xF075),
+            "loadflags" => self.register_instruction(0xF085),
+            "save" => self.save_load(0xF055, 0x5002),
+            "load" => self.save_load(0xF065, 0x5003),
+            "sprite" => {
+                let x = self.expect_register()? as u16;
+                let y = self.expect_register()? as u16;
+                let n = check(self.known_value()?, 0xF)? as u16;
+                self.emit(0xD000 | x << 8 | y << 4 | n)
+            }
+            "jump" => self.emit_address(0x1000, Fixup::Address),
+            "jump0" => self.emit_address(0xB000, Fixup::Address),
+            "native" => self.emit_address(0x0000, Fixup::Address),
+            "delay" => {
+                self.expect(":=")?;
+                self.register_instruction(0xF015)
+            }
+            "buzzer" => {
+                self.expect(":=")?;
+                self.register_instruction(0xF018)
+            }
+            "pitch" => {
+                self.expect(":=")?;
+                self.register_instruction(0xF03A)
+            }
+            "i" => self.index_statement(),
+            "if" => {
+                let condition = self.condition()?;
+                for instruction in &condition.setup {
+                    self.emit(*instruction)?;
+                }
+                match self.expect_token()?.as_str() {
+                    "then" => self.emit(condition.skip_if_false),
+                    "begin" => {
+                        self.emit(condition.skip_if_true)?;
+                        self.branches.push(self.here);
+                        self.emit(0x1000)
+                    }
+                    other => Err(format!("expected `then` or `begin`, found {}", other)),
+                }
+            }
+            "else" => {
+                let branch = self.branches.pop().ok_or("`else` without `begin`")?;
+                self.branches.push(self.here);
+                self.emit(0x1000)?;
+                self.patch_jump(branch, self.here)
+            }
+            "end" => {
+                let branch = self.branches.pop().ok_or("`end` without `begin`")?;
+                self.patch_jump(branch, self.here)
+            }
+            "loop" => {
+                self.loops.push(Loop { start: self.here, breaks: Vec::new() });
+                Ok(())
+            }
+            "while" => {
+                let condition = self.condition()?;
+                for instruction in &condition.setup {
+                    self.emit(*instruction)?;
+                }
+                self.emit(condition.skip_if_true)?;
+                let here = self.here;
+                self.loops.last_mut().ok_or("`while` outside of a loop")?.breaks.push(here);
+                self.emit(0x1000)
+            }
+            "again" => {
+                let active = self.loops.pop().ok_or("`again` without `loop`")?;
+                let start = check(active.start as i64, 0xFFF)? as u16;
+                self.emit(0x1000 | start)?;
+                for address in active.breaks {
+                    self.patch_jump(address, self.here)?;
+                }
+                Ok(())
+            }
+            _ => {
+                if let Some((arguments, body)) = self.macros.get(text).cloned() {
+                    if token.depth >= MAX_MACRO_DEPTH {
+                        return Err(format!("macro {} is nested more than {} times", text, MAX_MACRO_DEPTH));
+                    }
+                    return self.expand_macro(arguments, body, token.depth + 1);
+                }
+                if parse_number(text).is_some() || text == "{" || self.constants.contains_key(text) {
+                    self.tokens.push_front(token.clone());
+                    let value = self.known_value()?;
+                    let value = check_byte(value)?;
+                    return self.emit_byte(value);
+                }
+                // A name alone calls the subroutine at that label
+                self.tokens.push_front(token.clone());
+                self.emit_address(0x2000, Fixup::Address)
+            }
+        }
+    }
+
+    fn register_statement(&mut self, x: u8) -> Result<(), String> {
+        let x16 = (x as u16) << 8;
+        let operator = self.expect_token()?;
+        let rhs = self.expect_token()?;
+        if let Some(y) = self.register(&rhs) {
+            let y16 = (y as u16) << 4;
+            let opcode = match operator.as_str() {
+                ":=" => 0x8000,
+                "|=" => 0x8001,
+                "&=" => 0x8002,
+                "^=" => 0x8003,
+                "+=" => 0x8004,
+                "-=" => 0x8005,
+                ">>=" => 0x8006,
+                "=-" => 0x8007,
+                "<<=" => 0x800E,
+                _ => return Err(format!("invalid operator {}", operator)),
+            };
+            return self.emit(opcode | x16 | y16);
+        }
+
+        match (operator.as_str(), rhs.as_str()) {
+            (":=", "key") => self.emit(0xF00A | x16),
+            (":=", "delay") => self.emit(0xF007 | x16),
+            (":=", "random") => {
+                let mask = self.known_value()?;
+                let mask = check_byte(mask)? as u16;
+                self.emit(0xC000 | x16 | mask)
+            }
+            (":=", _) | ("+=", _) | ("-=", _) => {
+                self.tokens.push_front(Token { text: rhs, line: self.line, depth: 0 });
+                let value = self.known_value()?;
+                let value = check_byte(value)?;
+                match operator.as_str() {
+                    ":=" => self.emit(0x6000 | x16 | value as u16),
+                    "+=" => self.emit(0x7000 | x16 | value as u16),
+                    _ => self.emit(0x7000 | x16 | value.wrapping_neg() as u16),
+                }
+            }
+            _ => Err(format!("invalid operator {} {}", operator, rhs)),
+        }
+    }
+
+    fn index_statement(&mut self) -> Result<(), String> {
+        let operator = self.expect_token()?;
+        match operator.as_str() {
+            "+=" => self.register_instruction(0xF01E),
+            ":=" => match self.expect_token()?.as_str() {
+                "hex" => self.register_instruction(0xF029),
+                "bighex" => self.register_instruction(0xF030),
+                "long" => {
+                    self.emit(0xF000)?;
+                    self.emit_address(0x0000, Fixup::Long)
+                }
+                other => {
+                    self.tokens.push_front(Token { text: other.to_string(), line: self.line, depth: 0 });
+                    self.emit_address(0xA000, Fixup::Address)
+                }
+            },
+            _ => Err(format!("invalid operator i {}", operator)),
+        }
+    }
+
+    // `save vx`, or `save vx - vy` on XO-CHIP
+    fn save_load(&mut self, single: u16, range: u16) -> Result<(), String> {
+        let x = self.expect_register()? as u16;
+        if self.tokens.front().map(|token| token.text == "-").unwrap_or(false) {
+            self.expect_token()?;
+            let y = self.expect_register()? as u16;
+            return self.emit(range | x << 8 | y << 4);
+        }
+        self.emit(single | x << 8)
+    }
+
+    fn condition(&mut self) -> Result<Condition, String> {
+        let x = self.expect_register()? as u16;
+        let operator = self.expect_token()?;
+        match operator.as_str() {
+            "key" => return Ok(Condition { setup: vec![], skip_if_false: 0xE0A1 | x << 8, skip_if_true: 0xE09E | x << 8 }),
+            "-key" => return Ok(Condition { setup: vec![], skip_if_false: 0xE09E | x << 8, skip_if_true: 0xE0A1 | x << 8 }),
+            _ => {}
+        }
+
+        let rhs = self.expect_token()?;
+        let y = self.register(&rhs).map(|y| y as u16);
+        let n = match y {
+            Some(_) => 0,
+            None => {
+                self.tokens.push_front(Token { text: rhs, line: self.line, depth: 0 });
+                let value = self.known_value()?;
+                check_byte(value)? as u16
+            }
+        };
+        let equal = match y {
+            Some(y) => (0x9000 | x << 8 | y << 4, 0x5000 | x << 8 | y << 4),
+            None => (0x4000 | x << 8 | n, 0x3000 | x << 8 | n),
+        };
+        // vf := 1 if x >= rhs, else 0
+        let x_greater_or_equal = match y {
+            Some(y) => vec![0x8F00 | x << 4, 0x8F05 | y << 4],
+            None => vec![0x6F00 | n, 0x8F07 | x << 4],
+        };
+        // vf := 1 if rhs >= x, else 0
+        let rhs_greater_or_equal = match y {
+            Some(y) => vec![0x8F00 | y << 4, 0x8F05 | x << 4],
+            None => vec![0x6F00 | n, 0x8F05 | x << 4],
+        };
+        let vf_zero = (0x4F00, 0x3F00);
+        let vf_one = (0x3F00, 0x4F00);
+
+        let (setup, (skip_if_false, skip_if_true)) = match operator.as_str() {
+            "==" => (vec![], equal),
+            "!=" => (vec![], (equal.1, equal.0)),
+            "<" => (x_greater_or_equal, vf_zero),
+            ">=" => (x_greater_or_equal, vf_one),
+            ">" => (rhs_greater_or_equal, vf_zero),
+            "<=" => (rhs_greater_or_equal, vf_one),
+            _ => return Err(format!("invalid condition {}", operator)),
+        };
+        Ok(Condition { setup, skip_if_false, skip_if_true })
+    }
+
+    fn define_macro(&mut self) -> Result<(), String> {
+        let name = self.expect_token()?;
+        let mut arguments = Vec::new();
+        loop {
+            let token = self.expect_token()?;
+            if token == "{" {
+                break;
+            }
+            arguments.push(token);
+        }
+        let mut body = Vec::new();
+        let mut depth = 1;
+        loop {
+            let token = self.tokens.pop_front().ok_or("unterminated macro")?;
+            match token.text.as_str() {
+                "{" => depth += 1,
+                "}" => depth -= 1,
+                _ => {}
+            }
+            if depth == 0 {
+                break;
+            }
+            body.push(token);
+        }
+        self.macros.insert(name, (arguments, body));
+        Ok(())
+    }
+
+    fn expand_macro(&mut self, arguments: Vec<String>, body: Vec<Token>, depth: usize) -> Result<(), String> {
+        let mut values = HashMap::new();
+        for argument in arguments {
+            values.insert(argument, self.expect_token()?);
+        }
+        for token in body.into_iter().rev() {
+            let text = values.get(&token.text).cloned().unwrap_or(token.text);
+            self.tokens.push_front(Token { text, line: token.line, depth });
+        }
+        Ok(())
+    }
+
+    // `{ expression }`, evaluated from right to left without precedence, like Octo
+    fn calc(&mut self) -> Result<f64, String> {
+        self.expect("{")?;
+        let mut tokens = Vec::new();
+        loop {
+            let token = self.expect_token()?;
+            if token == "}" {
+                break;
+            }
+            tokens.push(token);
+        }
+        let mut position = 0;
+        let value = self.expression(&tokens, &mut position)?;
+        if position != tokens.len() {
+            return Err(format!("unexpected {} in expression", tokens[position]));
+        }
+        Ok(value)
+    }
+
+    fn expression(&self, tokens: &[String], position: &mut usize) -> Result<f64, String> {
+        let lhs = self.term(tokens, position)?;
+        let operator = match tokens.get(*position) {
+            Some(operator) if operator != ")" => operator.clone(),
+            _ => return Ok(lhs),
+        };
+        *position += 1;
+        let rhs = self.expression(tokens, position)?;
+        let boolean = |value: bool| if value { 1.0 } else { 0.0 };
+        Ok(match operator.as_str() {
+            "+" => lhs + rhs,
+            "-" => lhs - rhs,
+            "*" => lhs * rhs,
+            "/" => lhs / rhs,
+            "%" => lhs % rhs,
+            "&" => ((lhs as i64) & (rhs as i64)) as f64,
+            "|" => ((lhs as i64) | (rhs as i64)) as f64,
+            "^" => ((lhs as i64) ^ (rhs as i64)) as f64,
+            "<<" => shift(lhs, rhs, i64::checked_shl)?,
+            ">>" => shift(lhs, rhs, i64::checked_shr)?,
+            "pow" => lhs.powf(rhs),
+            "min" => lhs.min(rhs),
+            "max" => lhs.max(rhs),
+            "<" => boolean(lhs < rhs),
+            ">" => boolean(lhs > rhs),
+            "<=" => boolean(lhs <= rhs),
+            ">=" => boolean(lhs >= rhs),
+            "==" => boolean(lhs == rhs),
+            "!=" => boolean(lhs != rhs),
+            _ => return Err(format!("invalid operator {} in expression", operator)),
+        })
+    }
+
+    fn term(&self, tokens: &[String], position: &mut usize) -> Result<f64, String> {
+        let token = tokens.get(*position).ok_or("incomplete expression")?;
+        *position += 1;
+        let unary = |function: fn(f64) -> f64, position: &mut usize| -> Result<f64, String> {
+            Ok(function(self.term(tokens, position)?))
+        };
+        match token.as_str() {
+            "(" => {
+                let value = self.expression(tokens, position)?;
+                if tokens.get(*position).map(|token| token.as_str()) != Some(")") {
+                    return Err("missing ) in expression".to_string());
+                }
+                *position += 1;
+                Ok(value)
+            }
+            "-" => unary(|value| -value, position),
+            "~" => unary(|value| !(value as i64) as f64, position),
+            "!" => unary(|value| if value == 0.0 { 1.0 } else { 0.0 }, position),
+            "sin" => unary(f64::sin, position),
+            "cos" => unary(f64::cos, position),
+            "tan" => unary(f64::tan, position),
+            "exp" => unary(f64::exp, position),
+            "log" => unary(f64::ln, position),
+            "abs" => unary(f64::abs, position),
+            "sqrt" => unary(f64::sqrt, position),
+            "sign" => unary(f64::signum, position),
+            "ceil" => unary(f64::ceil, position),
+            "floor" => unary(f64::floor, position),
+            "@" => {
+                let address = self.term(tokens, position)? as usize;
+                Ok(*self.memory.get(address).unwrap_or(&0) as f64)
+            }
+            "HERE" => Ok(self.here as f64),
+            "PI" => Ok(std::f64::consts::PI),
+            "E" => Ok(std::f64::consts::E),
+            _ => parse_number(token).map(|value| value as f64)
+                .or_else(|| self.constants.get(token).copied())
+                .ok_or(format!("undefined name {} in expression", token)),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        if self.tokens.front().map(|token| token.text == "{").unwrap_or(false) {
+            return Ok(Value::Known(self.calc()?.floor() as i64));
+        }
+        let token = self.expect_token()?;
+        if let Some(value) = parse_number(&token) {
+            return Ok(Value::Known(value));
+        }
+        if let Some(value) = self.constants.get(&token) {
+            return Ok(Value::Known(value.floor() as i64));
+        }
+        if token.starts_with(':') || self.register(&token).is_some() {
+            return Err(format!("expected a value, found {}", token));
+        }
+        Ok(Value::Forward(token))
+    }
+
+    fn known_value(&mut self) -> Result<i64, String> {
+        match self.value()? {
+            Value::Known(value) => Ok(value),
+            Value::Forward(name) => Err(format!("undefined name {}", name)),
+        }
+    }
+
+    fn emit_address(&mut self, opcode: u16, fixup: Fixup) -> Result<(), String> {
+        let value = match self.value()? {
+            Value::Known(value) => value,
+            Value::Forward(name) => {
+                self.fixups.push((self.here, name, fixup, self.line));
+                0
+            }
+        };
+        match fixup {
+            Fixup::Long => self.emit(check(value, 0xFFFF)? as u16),
+            _ => self.emit(opcode | check(value, 0xFFF)? as u16),
+        }
+    }
+
+    // v0 and v1 := the address of a label, with `nibble` in the highest bits
+    fn emit_unpack(&mut self, nibble: Option<u8>) -> Result<(), String> {
+        let (high, low) = match self.value()? {
+            Value::Known(value) => match nibble {
+                Some(nibble) => {
+                    let value = check(value, 0xFFF)?;
+                    ((nibble << 4) | (value >> 8) as u8, value as u8)
+                }
+                None => {
+                    let value = check(value, 0xFFFF)?;
+                    ((value >> 8) as u8, value as u8)
+                }
+            },
+            Value::Forward(name) => {
+                let high = match nibble {
+                    Some(nibble) => Fixup::UnpackHigh(nibble),
+                    None => Fixup::HighByte,
+                };
+                // Operands of v0 := high and v1 := low
+                self.fixups.push((self.here + 1, name.clone(), high, self.line));
+                self.fixups.push((self.here + 3, name, Fixup::LowByte, self.line));
+                (0, 0)
+            }
+        };
+        self.emit(0x6000 | high as u16)?;
+        self.emit(0x6100 | low as u16)
+    }
+
+    fn register_instruction(&mut self, opcode: u16) -> Result<(), String> {
+        let x = self.expect_register()? as u16;
+        self.emit(opcode | x << 8)
+    }
+
+    fn patch_jump(&mut self, address: usize, target: usize) -> Result<(), String> {
+        let target = check(target as i64, 0xFFF)?;
+        self.memory[address] = 0x10 | (target >> 8) as u8;
+        self.memory[address + 1] = target as u8;
+        Ok(())
+    }
+
+    fn emit(&mut self, instruction: u16) -> Result<(), String> {
+        self.emit_byte((instruction >> 8) as u8)?;
+        self.emit_byte(instruction as u8)
+    }
+
+    fn emit_byte(&mut self, byte: u8) -> Result<(), String> {
+        if self.here >= ADDRESS_SPACE {
+            return Err("program does not fit in memory".to_string());
+        }
+        self.memory[self.here] = byte;
+        self.here += 1;
+        self.end = self.end.max(self.here);
+        Ok(())
+    }
+
+    fn define(&mut self, name: String, value: f64) -> Result<(), String> {
+        if self.register(&name).is_some() {
+            return Err(format!("cannot redefine register {}", name));
+        }
+        self.constants.insert(name, value);
+        Ok(())
+    }
+
+    fn register(&self, text: &str) -> Option<u8> {
+        if let Some(x) = self.aliases.get(text) {
+            return Some(*x);
+        }
+        let mut chars = text.chars();
+        match (chars.next(), chars.next(), chars.next()) {
+            (Some('v'), Some(digit), None) | (Some('V'), Some(digit), None) => digit.to_digit(16).map(|x| x as u8),
+            _ => None,
+        }
+    }
+
+    fn expect_register(&mut self) -> Result<u8, String> {
+        let token = self.expect_token()?;
+        self.register(&token).ok_or(format!("expected a register, found {}", token))
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        let token = self.expect_token()?;
+        if token != expected {
+            return Err(format!("expected {}, found {}", expected, token));
+        }
+        Ok(())
+    }
+
+    fn expect_token(&mut self) -> Result<String, String> {
+        self.next_token().map(|token| token.text).ok_or_else(|| "unexpected end of program".to_string())
+    }
+
+    fn next_token(&mut self) -> Option<Token> {
+        let token = self.tokens.pop_front()?;
+        self.line = token.line;
+        Some(token)
+    }
+}
+
+fn check(value: i64, max: i64) -> Result<i64, String> {
+    if value < 0 || value > max {
+        return Err(format!("{} does not fit in 0x{:X}", value, max));
+    }
+    Ok(value)
+}
+
+// Negative bytes are two's complement, e.g. v0 += -1
+fn check_byte(value: i64) -> Result<u8, String> {
+    if !(-128..=255).contains(&value) {
+        return Err(format!("{} does not fit in a byte", value));
+    }
+    Ok(value as u8)
+}
+
+// Shift of a calc expression, by 0 to 63 bits
+fn shift(lhs: f64, rhs: f64, function: fn(i64, u32) -> Option<i64>) -> Result<f64, String> {
+    u32::try_from(rhs as i64).ok()
+        .and_then(|rhs| function(lhs as i64, rhs))
+        .map(|value| value as f64)
+        .ok_or(format!("shift by {} out of range", rhs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::assemble;
+
+    #[test]
+    fn labels_and_forward_references() {
+        let source = "
+            : main
+              jump later
+              v0 := 1
+            : later
+              v1 := 2
+              jump main
+        ";
+        assert_eq!(assemble(source), Ok(vec![0x12, 0x04, 0x60, 0x01, 0x61, 0x02, 0x12, 0x00]));
+    }
+
+    #[test]
+    fn constants_and_calc() {
+        let source = "
+            :const SPEED 3
+            :calc DOUBLE { SPEED * 2 }
+            :calc FLAG { 1 << 4 }
+            v0 := SPEED
+            v1 := DOUBLE
+            v2 := FLAG
+        ";
+        assert_eq!(assemble(source), Ok(vec![0x60, 0x03, 0x61, 0x06, 0x62, 0x10]));
+    }
+
+    #[test]
+    fn macros() {
+        let source = "
+            :macro twice REGISTER { REGISTER += 1 REGISTER += 1 }
+            twice v3
+            twice v4
+        ";
+        assert_eq!(assemble(source), Ok(vec![0x73, 0x01, 0x73, 0x01, 0x74, 0x01, 0x74, 0x01]));
+    }
+
+    #[test]
+    fn aliases() {
+        let source = "
+            :alias score v5
+            score := 7
+            score += score
+        ";
+        assert_eq!(assemble(source), Ok(vec![0x65, 0x07, 0x85, 0x54]));
+    }
+
+    #[test]
+    fn undefined_name() {
+        assert_eq!(assemble("v0 := 1\njump nowhere"), Err("line 2: undefined name nowhere".to_string()));
+    }
+
+    #[test]
+    fn out_of_range_shifts() {
+        assert_eq!(assemble(":calc X { 1 << 64 }"), Err("line 1: shift by 64 out of range".to_string()));
+        assert_eq!(assemble(":calc X { 1 >> -1 }"), Err("line 1: shift by -1 out of range".to_string()));
+    }
+
+    #[test]
+    fn stringmode_is_not_supported() {
+        let error = assemble(":stringmode text \"abc\" { :byte VALUE }").unwrap_err();
+        assert_eq!(error, "line 1: :stringmode is not supported");
+    }
+
+    #[test]
+    fn recursive_macros() {
+        let error = assemble(":macro forever { forever }\nforever").unwrap_err();
+        assert_eq!(error, "line 1: macro forever is nested more than 64 times");
+        assert!(assemble(":macro ping { pong }\n:macro pong { ping }\nping").is_err());
+    }
+
+    #[test]
+    fn loops() {
+        assert_eq!(assemble("loop v0 += 1 again"), Ok(vec![0x70, 0x01, 0x12, 0x00]));
+        assert_eq!(assemble(":org 0x1000 loop again"), Err("line 1: 4096 does not fit in 0xFFF".to_string()));
+    }
+
+    #[test]
+    fn org_below_the_program() {
+        assert_eq!(assemble(":org 0x300 v0 := 1"), Ok([vec![0; 0x100], vec![0x60, 0x01]].concat()));
+        assert_eq!(assemble(":org 0x100 v0 := 1"), Err("line 1: :org 0x100 is below the program start 0x200".to_string()));
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::palette::mix;
+
+pub const GLYPH_WIDTH: usize = 3;
+pub const GLYPH_HEIGHT: usize = 5;
+// Space between characters and between lines, in font pixels
+const GLYPH_SPACING: usize = 1;
+const LINE_SPACING: usize = 2;
+const MARGIN: usize = 2;
+
+const TEXT_COLOR: u32 = 0x00FF_FFFF;
+const HIGHLIGHT_COLOR: u32 = 0x00FF_D700;
+const BACKDROP_COLOR: u32 = 0;
+const BACKDROP_OPACITY: f32 = 0.7;
+
+const MESSAGE_DURATION: Duration = Duration::from_secs(2);
+const MAX_MESSAGES: usize = 3;
+const FPS_INTERVAL: Duration = Duration::from_secs(1);
+
+/*
+ * Text drawn over the scaled buffer
+ * top left    => status: FPS, speed, pause
+ * bottom left => messages, e.g. after saving a screenshot
+ * center      => pause menu
+ */
+pub struct Overlay {
+    show_fps: bool,
+    messages: VecDeque<(String, Instant)>,
+    frames: u32,
+    fps_since: Instant,
+    fps: u32,
+}
+
+impl Overlay {
+    pub fn new(show_fps: bool) -> Self {
+        Overlay {
+            show_fps,
+            messages: VecDeque::new(),
+            frames: 0,
+            fps_since: Instant::now(),
+            fps: 0,
+        }
+    }
+
+    pub fn toggle_fps(&mut self) {
+        self.show_fps = !self.show_fps;
+    }
+
+    // Shows `message` for a few seconds, it is also printed
+    pub fn notify(&mut self, message: String) {
+        println!("{}", message);
+        self.messages.push_back((message, Instant::now()));
+        if self.messages.len() > MAX_MESSAGES {
+            self.messages.pop_front();
+        }
+    }
+
+    pub fn frame_rendered(&mut self) {
+        self.frames += 1;
+        let elapsed = self.fps_since.elapsed();
+        if elapsed >= FPS_INTERVAL {
+            self.fps = (self.frames as f64 / elapsed.as_secs_f64()).round() as u32;
+            self.frames = 0;
+            self.fps_since = Instant::now();
+        }
+    }
+
+    // `status` is shown with the FPS, or alone when not empty
+    pub fn draw(&mut self, canvas: &mut Canvas, status: &str) {
+        let now = Instant::now();
+        self.messages.retain(|(_, shown)| now.duration_since(*shown) < MESSAGE_DURATION);
+
+        let status = match (self.show_fps, status.is_empty()) {
+            (true, true) => format!("FPS {}", self.fps),
+            (true, false) => format!("FPS {}  {}", self.fps, status),
+            (false, _) => status.to_string(),
+        };
+        if !status.is_empty() {
+            canvas.draw_lines(&[status.as_str()], MARGIN, MARGIN, None);
+        }
+
+        let lines: Vec<&str> = self.messages.iter().map(|(message, _)| message.as_str()).collect();
+        if !lines.is_empty() {
+            let y = canvas.height_in_font_pixels().saturating_sub(MARGIN + text_height(lines.len()));
+            canvas.draw_lines(&lines, MARGIN, y, None);
+        }
+    }
+}
+
+// Scaled buffer, drawn on with font pixels of `pixel_size` x `pixel_size`
+pub struct Canvas<'a> {
+    buffer: &'a mut [u32],
+    width: usize,
+    height: usize,
+    pixel_size: usize,
+}
+
+impl<'a> Canvas<'a> {
+    pub fn new(buffer: &'a mut [u32], width: usize, height: usize, pixel_size: usize) -> Self {
+        Canvas { buffer, width, height, pixel_size: pixel_size.max(1) }
+    }
+
+    pub fn width_in_font_pixels(&self) -> usize {
+        self.width / self.pixel_size
+    }
+
+    pub fn height_in_font_pixels(&self) -> usize {
+        self.height / self.pixel_size
+    }
+
+    // Lines of text on a backdrop, at (x, y) in font pixels, the `highlighted` line in another color
+    pub fn draw_lines(&mut self, lines: &[&str], x: usize, y: usize, highlighted: Option<usize>) {
+        let width = lines.iter().map(|line| text_width(line)).max().unwrap_or(0);
+        self.darken(x.saturating_sub(1), y.saturating_sub(1), width + 2, text_height(lines.len()) + 2);
+        for (i, line) in lines.iter().enumerate() {
+            let color = if highlighted == Some(i) { HIGHLIGHT_COLOR } else { TEXT_COLOR };
+            self.draw_text(line, x, y + i * (GLYPH_HEIGHT + LINE_SPACING), color);
+        }
+    }
+
+    // Text without backdrop, at (x, y) in font pixels
+    pub fn draw_text(&mut self, text: &str, x: usize, y: usize, color: u32) {
+        for (n, character) in text.chars().enumerate() {
+            let glyph = glyph(character);
+            let glyph_x = x + n * (GLYPH_WIDTH + GLYPH_SPACING);
+            for (j, row) in glyph.iter().enumerate() {
+                for i in 0..GLYPH_WIDTH {
+                    if row & (0b100 >> i) > 0 {
+                        self.fill(glyph_x + i, y + j, 1, 1, |_| color);
+                    }
+                }
+            }
+        }
+    }
+
+    // Rectangle of one color, in font pixels
+    pub fn draw_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: u32) {
+        self.fill(x, y, width, height, |_| color);
+    }
+
+    fn darken(&mut self, x: usize, y: usize, width: usize, height: usize) {
+        self.fill(x, y, width, height, |pixel| mix(pixel, BACKDROP_COLOR, 1.0 - BACKDROP_OPACITY));
+    }
+
+    fn fill(&mut self, x: usize, y: usize, width: usize, height: usize, color: impl Fn(u32) -> u32) {
+        let x_end = ((x + width) * self.pixel_size).min(self.width);
+        let y_end = ((y + height) * self.pixel_size).min(self.height);
+        for j in (y * self.pixel_size)..y_end {
+            for i in (x * self.pixel_size)..x_end {
+                let pixel = &mut self.buffer[i + j * self.width];
+                *pixel = color(*pixel);
+            }
+        }
+    }
+}
+
+pub fn text_width(text: &str) -> usize {
+    text.chars().count() * (GLYPH_WIDTH + GLYPH_SPACING)
+}
+
+pub fn text_height(lines: usize) -> usize {
+    lines * (GLYPH_HEIGHT + LINE_SPACING)
+}
+
+// 3x5 font, one row per byte, the lowest 3 bits from left to right
+fn glyph(character: char) -> [u8; GLYPH_HEIGHT] {
+    match character.to_ascii_uppercase() {
+        'A' => [0b010, 0b101, 0b111, 0b101, 0b101],
+        'B' => [0b110, 0b101, 0b110, 0b101, 0b110],
+        'C' => [0b011, 0b100, 0b100, 0b100, 0b011],
+        'D' => [0b110, 0b101, 0b101, 0b101, 0b110],
+        'E' => [0b111, 0b100, 0b110, 0b100, 0b111],
+        'F' => [0b111, 0b100, 0b110, 0b100, 0b100],
+        'G' => [0b011, 0b100, 0b101, 0b101, 0b011],
+        'H' => [0b101, 0b101, 0b111, 0b101, 0b101],
+        'I' => [0b111, 0b010, 0b010, 0b010, 0b111],
+        'J' => [0b001, 0b001, 0b001, 0b101, 0b010],
+        'K' => [0b101, 0b101, 0b110, 0b101, 0b101],
+        'L' => [0b100, 0b100, 0b100, 0b100, 0b111],
+        'M' => [0b101, 0b111, 0b111, 0b101, 0b101],
+        'N' => [0b110, 0b101, 0b101, 0b101, 0b101],
+        'O' => [0b010, 0b101, 0b101, 0b101, 0b010],
+        'P' => [0b110, 0b101, 0b110, 0b100, 0b100],
+        'Q' => [0b010, 0b101, 0b101, 0b110, 0b011],
+        'R' => [0b110, 0b101, 0b110, 0b101, 0b101],
+        'S' => [0b011, 0b100, 0b010, 0b001, 0b110],
+        'T' => [0b111, 0b010, 0b010, 0b010, 0b010],
+        'U' => [0b101, 0b101, 0b101, 0b101, 0b111],
+        'V' => [0b101, 0b101, 0b101, 0b101, 0b010],
+        'W' => [0b101, 0b101, 0b111, 0b111, 0b101],
+        'X' => [0b101, 0b101, 0b010, 0b101, 0b101],
+        'Y' => [0b101, 0b101, 0b010, 0b010, 0b010],
+        'Z' => [0b111, 0b001, 0b010, 0b100, 0b111],
+        '0' => [0b111, 0b101, 0b101, 0b101, 0b111],
+        '1' => [0b010, 0b110, 0b010, 0b010, 0b111],
+        '2' => [0b110, 0b001, 0b010, 0b100, 0b111],
+        '3' => [0b110, 0b001, 0b010, 0b001, 0b110],
+        '4' => [0b101, 0b101, 0b111, 0b001, 0b001],
+        '5' => [0b111, 0b100, 0b110, 0b001, 0b110],
+        '6' => [0b011, 0b100, 0b111, 0b101, 0b111],
+        '7' => [0b111, 0b001, 0b010, 0b010, 0b010],
+        '8' => [0b111, 0b101, 0b111, 0b101, 0b111],
+        '9' => [0b111, 0b101, 0b111, 0b001, 0b110],
+        ' ' => [0b000, 0b000, 0b000, 0b000, 0b000],
+        '.' => [0b000, 0b000, 0b000, 0b000, 0b010],
+        ',' => [0b000, 0b000, 0b000, 0b010, 0b100],
+        ':' => [0b000, 0b010, 0b000, 0b010, 0b000],
+        '-' => [0b000, 0b000, 0b111, 0b000, 0b000],
+        '+' => [0b000, 0b010, 0b111, 0b010, 0b000],
+        '=' => [0b000, 0b111, 0b000, 0b111, 0b000],
+        '_' => [0b000, 0b000, 0b000, 0b000, 0b111],
+        '/' => [0b001, 0b001, 0b010, 0b100, 0b100],
+        '%' => [0b101, 0b001, 0b010, 0b100, 0b101],
+        '<' => [0b001, 0b010, 0b100, 0b010, 0b001],
+        '>' => [0b100, 0b010, 0b001, 0b010, 0b100],
+        '(' => [0b010, 0b100, 0b100, 0b100, 0b010],
+        ')' => [0b010, 0b001, 0b001, 0b001, 0b010],
+        '[' => [0b110, 0b100, 0b100, 0b100, 0b110],
+        ']' => [0b011, 0b001, 0b001, 0b001, 0b011],
+        '!' => [0b010, 0b010, 0b010, 0b000, 0b010],
+        '\'' => [0b010, 0b010, 0b000, 0b000, 0b000],
+        _ => [0b110, 0b001, 0b010, 0b000, 0b010],
+    }
+}
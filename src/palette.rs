@@ -0,0 +1,69 @@
+pub const NUMBER_OF_COLORS: usize = 4;
+
+pub const PALETTE_NAMES: [&str; 4] = ["default", "green-phosphor", "amber", "lcd"];
+
+// Options overriding the palette color of each logical pixel value
+pub const PALETTE_COLOR_OPTIONS: [&str; NUMBER_OF_COLORS] = ["bg", "fg", "fg2", "blend-color"];
+
+/*
+ * Maps the logical pixel values stored by the core to 0RGB colors
+ * 0 => background
+ * 1 => foreground (first plane)
+ * 2 => second plane
+ * 3 => both planes
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Palette {
+    colors: [u32; NUMBER_OF_COLORS],
+}
+
+impl Palette {
+    pub fn new(colors: [u32; NUMBER_OF_COLORS]) -> Self {
+        Palette { colors }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "default" => Some(Palette::new([0x0000_0000, 0x00FF_FFFF, 0x00AA_AAAA, 0x0055_5555])),
+            "green-phosphor" => Some(Palette::new([0x000A_1A0A, 0x0033_FF66, 0x0019_9933, 0x00B3_FFCC])),
+            "amber" => Some(Palette::new([0x001A_0F00, 0x00FF_B000, 0x0099_6600, 0x00FF_DD88])),
+            "lcd" => Some(Palette::new([0x009C_BD0F, 0x000F_380F, 0x0030_6230, 0x008B_AC0F])),
+            _ => None
+        }
+    }
+
+    // Replaces the color of a logical pixel value, e.g. 2 for the second plane
+    pub fn with_color(mut self, pixel: u8, color: u32) -> Self {
+        self.colors[pixel as usize % NUMBER_OF_COLORS] = color;
+        self
+    }
+
+    pub fn color(&self, pixel: u8) -> u32 {
+        self.colors[pixel as usize % NUMBER_OF_COLORS]
+    }
+}
+
+// Parses colors written as RRGGBB, #RRGGBB or 0xRRGGBB
+pub fn parse_color(value: &str) -> Result<u32, String> {
+    let hex = value.trim_start_matches('#').trim_start_matches("0x");
+    u32::from_str_radix(hex, 16)
+        .ok()
+        .filter(|color| hex.len() == 6 && *color <= 0x00FF_FFFF)
+        .ok_or(format!("invalid color {}", value))
+}
+
+// Keeps `weight` of `from` and (1 - `weight`) of `to`, channel by channel
+pub fn mix(from: u32, to: u32, weight: f32) -> u32 {
+    blend(from, to, weight, 1.0 - weight)
+}
+
+// Sum of `from_weight` of `from` and `to_weight` of `to`, channel by channel, saturated
+pub fn blend(from: u32, to: u32, from_weight: f32, to_weight: f32) -> u32 {
+    (0..3).fold(0, |acc, channel| {
+        let shift = channel * 8;
+        let a = ((from >> shift) & 0xFF) as f32;
+        let b = ((to >> shift) & 0xFF) as f32;
+        let value = (a * from_weight + b * to_weight).round() as u32;
+        acc | (value.min(0xFF) << shift)
+    })
+}
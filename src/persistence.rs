@@ -0,0 +1,93 @@
+use std::collections::VecDeque;
+
+use crate::chip8::{DISPLAY_SIZE, PIXEL_OFF};
+use crate::palette::{Palette, mix};
+
+const DEFAULT_DECAY: f32 = 0.6;
+const DEFAULT_BLEND_FRAMES: usize = 3;
+
+/*
+ * Anti-flicker filters applied before scaling
+ * none     => raw frames
+ * decay:f  => pixels turning off fade out, keeping f of their color each frame
+ * blend:n  => a pixel is lit if it was lit in any of the last n frames
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PersistenceMode {
+    None,
+    Decay(f32),
+    Blend(usize),
+}
+
+impl PersistenceMode {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut parts = value.splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        let parameter = parts.next();
+        let invalid = || format!("invalid persistence mode {}", value);
+        match (name, parameter) {
+            ("none", None) => Ok(PersistenceMode::None),
+            ("decay", None) => Ok(PersistenceMode::Decay(DEFAULT_DECAY)),
+            ("decay", Some(decay)) => decay.parse::<f32>().ok()
+                .filter(|decay| (0.0..1.0).contains(decay))
+                .map(PersistenceMode::Decay)
+                .ok_or_else(invalid),
+            ("blend", None) => Ok(PersistenceMode::Blend(DEFAULT_BLEND_FRAMES)),
+            ("blend", Some(frames)) => frames.parse::<usize>().ok()
+                .filter(|frames| *frames > 0)
+                .map(PersistenceMode::Blend)
+                .ok_or_else(invalid),
+            _ => Err(invalid())
+        }
+    }
+}
+
+pub struct Persistence {
+    mode: PersistenceMode,
+    history: VecDeque<[u8; DISPLAY_SIZE]>,
+    // Empty until the first frame, which starts from the palette background
+    frame: Vec<u32>,
+}
+
+impl Persistence {
+    pub fn new(mode: PersistenceMode) -> Self {
+        Persistence {
+            mode,
+            history: VecDeque::new(),
+            frame: Vec::new(),
+        }
+    }
+
+    // Returns the colors of the filtered frame at native resolution
+    pub fn apply(&mut self, display: &[u8; DISPLAY_SIZE], palette: &Palette) -> &[u32] {
+        if self.frame.is_empty() {
+            self.frame = vec![palette.color(PIXEL_OFF); DISPLAY_SIZE];
+        }
+        match self.mode {
+            PersistenceMode::None => {
+                for (color, pixel) in self.frame.iter_mut().zip(display.iter()) {
+                    *color = palette.color(*pixel);
+                }
+            }
+            PersistenceMode::Decay(decay) => {
+                for (color, pixel) in self.frame.iter_mut().zip(display.iter()) {
+                    let target = palette.color(*pixel);
+                    *color = if *pixel == PIXEL_OFF {
+                        mix(*color, target, decay)
+                    } else {
+                        target
+                    };
+                }
+            }
+            PersistenceMode::Blend(frames) => {
+                self.history.push_front(*display);
+                self.history.truncate(frames);
+                for (i, color) in self.frame.iter_mut().enumerate() {
+                    let pixel = self.history.iter().fold(PIXEL_OFF, |acc, frame| acc | frame[i]);
+                    *color = palette.color(pixel);
+                }
+            }
+        }
+        &self.frame
+    }
+}
@@ -0,0 +1,176 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::screenshot::to_rgb;
+
+const RECORDING_FRAME_RATE: u64 = 60;
+const GIF_MAX_COLORS: usize = 256;
+const GIF_QUANTIZATION_SPEED: i32 = 10;
+const CENTISECONDS_PER_SECOND: u64 = 100;
+// Shortest GIF frame delay, browsers and most viewers slow down frames of 1 cs or less to about 10 cs
+const GIF_MIN_DELAY: u64 = 2;
+
+/*
+ * Records rendered frames to a video file, the format is picked from the extension
+ * .gif => animated GIF, identical consecutive frames are merged, and a frame shown for less
+ *         than GIF_MIN_DELAY is replaced by the next one
+ * .y4m => uncompressed YUV4MPEG2 4:4:4 stream, one frame per rendered frame
+ */
+pub struct Recorder {
+    encoder: VideoEncoder,
+    width: usize,
+    height: usize,
+    scale: usize,
+}
+
+enum VideoEncoder {
+    Gif(GifEncoder),
+    Y4m(BufWriter<File>),
+}
+
+struct GifEncoder {
+    encoder: gif::Encoder<BufWriter<File>>,
+    // Last frame, written once a different frame arrives, and how many times it was rendered
+    pending: Option<(Vec<u8>, usize)>,
+    // Number of frames already written, used to round delays without drifting
+    elapsed_frames: u64,
+}
+
+impl Recorder {
+    pub fn create(path: &Path, width: usize, height: usize, scale: usize) -> Result<Self, Box<dyn std::error::Error>> {
+        let scaled_width = width * scale;
+        let scaled_height = height * scale;
+        let writer = BufWriter::new(File::create(path)?);
+        let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("");
+        let encoder = match extension {
+            "gif" => {
+                let mut encoder = gif::Encoder::new(writer, scaled_width as u16, scaled_height as u16, &[])?;
+                encoder.set_repeat(gif::Repeat::Infinite)?;
+                VideoEncoder::Gif(GifEncoder { encoder, pending: None, elapsed_frames: 0 })
+            }
+            "y4m" => {
+                let mut writer = writer;
+                writeln!(writer, "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444", scaled_width, scaled_height, RECORDING_FRAME_RATE)?;
+                VideoEncoder::Y4m(writer)
+            }
+            _ => return Err(format!("unsupported video format {}, expected .gif or .y4m", path.display()).into())
+        };
+
+        Ok(Recorder { encoder, width, height, scale })
+    }
+
+    // `colors` is a frame at native resolution
+    pub fn record(&mut self, colors: &[u32]) -> Result<(), Box<dyn std::error::Error>> {
+        let rgb = self.scale_frame(colors);
+        let scaled_width = self.width * self.scale;
+        let scaled_height = self.height * self.scale;
+        match &mut self.encoder {
+            VideoEncoder::Gif(gif) => {
+                let start = gif.elapsed_frames;
+                match &mut gif.pending {
+                    Some((pending, count)) if *pending == rgb => *count += 1,
+                    Some((pending, count)) if GifEncoder::delay(start, *count) < GIF_MIN_DELAY => {
+                        *pending = rgb;
+                        *count += 1;
+                    }
+                    _ => {
+                        gif.flush(scaled_width, scaled_height)?;
+                        gif.pending = Some((rgb, 1));
+                    }
+                }
+            }
+            VideoEncoder::Y4m(writer) => {
+                writer.write_all(b"FRAME\n")?;
+                let mut planes: Vec<Vec<u8>> = (0..3).map(|_| Vec::with_capacity(rgb.len() / 3)).collect();
+                for pixel in rgb.chunks(3) {
+                    let (r, g, b) = (pixel[0] as f32, pixel[1] as f32, pixel[2] as f32);
+                    planes[0].push((16.0 + 0.257 * r + 0.504 * g + 0.098 * b).round() as u8);
+                    planes[1].push((128.0 - 0.148 * r - 0.291 * g + 0.439 * b).round() as u8);
+                    planes[2].push((128.0 + 0.439 * r - 0.368 * g - 0.071 * b).round() as u8);
+                }
+                for plane in planes {
+                    writer.write_all(&plane)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let scaled_width = self.width * self.scale;
+        let scaled_height = self.height * self.scale;
+        match &mut self.encoder {
+            VideoEncoder::Gif(gif) => gif.flush(scaled_width, scaled_height)?,
+            VideoEncoder::Y4m(writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+
+    fn scale_frame(&self, colors: &[u32]) -> Vec<u8> {
+        let scaled_width = self.width * self.scale;
+        let scaled_height = self.height * self.scale;
+        let mut rgb = Vec::with_capacity(scaled_width * scaled_height * 3);
+        for j in 0..scaled_height {
+            for i in 0..scaled_width {
+                rgb.extend_from_slice(&to_rgb(colors[i / self.scale + (j / self.scale) * self.width]));
+            }
+        }
+        rgb
+    }
+}
+
+impl GifEncoder {
+    fn flush(&mut self, width: usize, height: usize) -> Result<(), Box<dyn std::error::Error>> {
+        let (rgb, count) = match self.pending.take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+
+        let mut frame = Self::indexed_frame(&rgb, width as u16, height as u16)
+            .unwrap_or_else(|| gif::Frame::from_rgb_speed(width as u16, height as u16, &rgb, GIF_QUANTIZATION_SPEED));
+        // Only the last frame can be shorter, it is then shown a bit longer
+        frame.delay = Self::delay(self.elapsed_frames, count).max(GIF_MIN_DELAY) as u16;
+        self.elapsed_frames += count as u64;
+
+        self.encoder.write_frame(&frame)?;
+        Ok(())
+    }
+
+    // Exact palette, unless the frame has too many colors (e.g. with persistence)
+    fn indexed_frame(rgb: &[u8], width: u16, height: u16) -> Option<gif::Frame<'static>> {
+        let mut palette: HashMap<&[u8], u8> = HashMap::new();
+        let mut indices = Vec::with_capacity(rgb.len() / 3);
+        for pixel in rgb.chunks(3) {
+            let next_index = palette.len();
+            let index = *palette.entry(pixel).or_insert(next_index as u8);
+            if palette.len() > GIF_MAX_COLORS {
+                return None;
+            }
+            indices.push(index);
+        }
+
+        let mut colors = vec![0; palette.len() * 3];
+        for (color, index) in palette {
+            colors[index as usize * 3..index as usize * 3 + 3].copy_from_slice(color);
+        }
+        Some(gif::Frame {
+            width,
+            height,
+            buffer: Cow::Owned(indices),
+            palette: Some(colors),
+            ..gif::Frame::default()
+        })
+    }
+
+    // Centiseconds of `count` frames after `start` frames, rounded without drifting
+    fn delay(start: u64, count: usize) -> u64 {
+        Self::to_centiseconds(start + count as u64) - Self::to_centiseconds(start)
+    }
+
+    fn to_centiseconds(frames: u64) -> u64 {
+        (frames * CENTISECONDS_PER_SECOND + RECORDING_FRAME_RATE / 2) / RECORDING_FRAME_RATE
+    }
+}
@@ -0,0 +1,488 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::audio::AudioSink;
+use crate::chip8::{Chip8, NUMBER_OF_KEYS};
+
+pub const TIMER_FREQUENCY: f64 = 60.0;
+
+// COSMAC VIP 1802 machine cycles per second, 8 clock cycles at 1.7609 MHz
+const VIP_MACHINE_CYCLE_FREQUENCY: f64 = 1_760_900.0 / 8.0;
+// Machine cycles of each 60 Hz frame taken from the interpreter: the display DMA,
+// 128 lines of 8 bytes at one cycle per byte, and the interrupt routine updating the timers
+const VIP_DISPLAY_DMA_CYCLES: u64 = 128 * 8;
+const VIP_INTERRUPT_CYCLES: u64 = 46;
+
+pub const TIMING_MODES: [&str; 2] = ["fixed", "vip"];
+
+/*
+ * fixed => every instruction takes the same time, at the CPU frequency
+ * vip   => every instruction takes its COSMAC VIP machine cycles, and Dxyn waits for the vertical blank
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimingMode {
+    Fixed,
+    Vip,
+}
+
+impl TimingMode {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "fixed" => Ok(TimingMode::Fixed),
+            "vip" => Ok(TimingMode::Vip),
+            _ => Err(format!("invalid timing mode {}", value))
+        }
+    }
+}
+
+// Pending cycles this close to a whole number are rounded to it
+const CYCLE_EPSILON: f64 = 1e-6;
+
+// Wall clock time spent emulating per frame at unlimited speed, leaving time to render
+const UNLIMITED_FRAME_BUDGET: Duration = Duration::from_millis(12);
+
+// From slowest to fastest
+pub const SPEEDS: [Speed; 6] = [
+    Speed::Multiplier(0.25),
+    Speed::Multiplier(0.5),
+    Speed::Multiplier(1.0),
+    Speed::Multiplier(2.0),
+    Speed::Multiplier(4.0),
+    Speed::Unlimited,
+];
+pub const NORMAL_SPEED: Speed = Speed::Multiplier(1.0);
+
+// Emulated time per wall clock time
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Speed {
+    Multiplier(f64),
+    // As fast as the host allows
+    Unlimited,
+}
+
+impl Speed {
+    // e.g. 0.5, 2 or unlimited
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "unlimited" => Ok(Speed::Unlimited),
+            _ => value.trim_end_matches('x').parse::<f64>().ok()
+                .filter(|multiplier| *multiplier > 0.0)
+                .map(Speed::Multiplier)
+                .ok_or(format!("invalid speed {}", value))
+        }
+    }
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Speed::Multiplier(multiplier) => write!(f, "{}x", multiplier),
+            Speed::Unlimited => write!(f, "unlimited"),
+        }
+    }
+}
+
+// Input holding keypad keys, a key is down while any of them holds it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeySource {
+    Keyboard,
+    Gamepad,
+    Panel,
+}
+
+// Keypad change, applied before the first instruction at or after `cycle`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyEvent {
+    pub cycle: u64,
+    pub source: KeySource,
+    pub key: usize,
+    pub pressed: bool,
+}
+
+/*
+ * Drives the core in emulated time.
+ * Fractions of cycles are carried over between calls, and the timers tick
+ * exactly 60 times per second of emulated time, between the instructions
+ * that fall before and after each tick.
+ */
+pub struct Scheduler {
+    // Instructions per second of emulated time, in fixed timing
+    cpu_frequency: f64,
+    timing: TimingMode,
+    speed: Speed,
+    paused: bool,
+    // Cycles owed to the core, less than 1 after each run
+    pending_cycles: f64,
+    // Cycles elapsed and timer ticks since the start
+    cycles: u64,
+    ticks: u64,
+    // By cycle, in the order they were queued for the same cycle
+    key_events: VecDeque<KeyEvent>,
+    // Sources holding each key, one bit per KeySource
+    held_keys: [u8; NUMBER_OF_KEYS],
+}
+
+impl Scheduler {
+    pub fn new(cpu_frequency: f64, timing: TimingMode) -> Self {
+        Scheduler {
+            cpu_frequency,
+            timing,
+            speed: NORMAL_SPEED,
+            paused: false,
+            pending_cycles: 0.0,
+            cycles: 0,
+            ticks: 0,
+            key_events: VecDeque::new(),
+            held_keys: [0; NUMBER_OF_KEYS],
+        }
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    pub fn set_speed(&mut self, speed: Speed) {
+        self.speed = speed;
+    }
+
+    // Next speed in SPEEDS, `step` is 1 for faster and -1 for slower
+    pub fn change_speed(&mut self, step: isize) {
+        let current = SPEEDS.iter().position(|speed| *speed == self.speed).unwrap_or(2) as isize;
+        let next = (current + step).clamp(0, SPEEDS.len() as isize - 1);
+        self.speed = SPEEDS[next as usize];
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.paused = !self.paused;
+    }
+
+    // Emulated seconds since the start
+    pub fn time(&self) -> f64 {
+        self.cycles as f64 / self.cycle_frequency()
+    }
+
+    // Presses `key` at the current emulated time
+    pub fn key_down(&mut self, source: KeySource, key: usize) {
+        self.queue_key(source, key, true, 0.0);
+    }
+
+    // Releases `key` at the current emulated time
+    pub fn key_up(&mut self, source: KeySource, key: usize) {
+        self.queue_key(source, key, false, 0.0);
+    }
+
+    // Presses or releases `key` after `delay` seconds of emulated time
+    pub fn queue_key(&mut self, source: KeySource, key: usize, pressed: bool, delay: f64) {
+        let cycle = self.cycles + (delay * self.cycle_frequency()).round() as u64;
+        self.queue_key_event(KeyEvent { cycle, source, key, pressed });
+    }
+
+    // Keys past F are ignored
+    pub fn queue_key_event(&mut self, event: KeyEvent) {
+        if event.key >= NUMBER_OF_KEYS {
+            return;
+        }
+        let index = self.key_events.iter().position(|queued| queued.cycle > event.cycle).unwrap_or(self.key_events.len());
+        self.key_events.insert(index, event);
+    }
+
+    // Drops the key events not applied yet and the held keys, e.g. when the keypad is released while the menu is open
+    pub fn release_keys(&mut self) {
+        self.key_events.clear();
+        self.held_keys = [0; NUMBER_OF_KEYS];
+    }
+
+    // Emulates `wall_seconds` of wall clock time at the current speed, nothing while paused
+    pub fn run_frame(&mut self, chip: &mut Chip8, wall_seconds: f64, audio: &mut dyn AudioSink) -> Result<(), Box<dyn std::error::Error>> {
+        if self.paused {
+            return Ok(());
+        }
+        match self.speed {
+            Speed::Multiplier(multiplier) => self.run(chip, wall_seconds * multiplier, audio),
+            Speed::Unlimited => {
+                let start = Instant::now();
+                while start.elapsed() < UNLIMITED_FRAME_BUDGET {
+                    self.run(chip, 1.0 / TIMER_FREQUENCY, audio)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Emulates `seconds` of emulated time, e.g. one frame when advancing frame by frame while paused
+    pub fn run(&mut self, chip: &mut Chip8, seconds: f64, audio: &mut dyn AudioSink) -> Result<(), Box<dyn std::error::Error>> {
+        let cycle_frequency = self.cycle_frequency();
+        self.pending_cycles += seconds * cycle_frequency;
+        // Sums of frame durations fall just short of whole cycles, e.g. 60 frames of 700 / 60 cycles
+        let whole_cycles = self.pending_cycles.round();
+        if (self.pending_cycles - whole_cycles).abs() < CYCLE_EPSILON {
+            self.pending_cycles = whole_cycles;
+        }
+        while self.pending_cycles >= 1.0 {
+            while self.ticks < self.ticks_due() {
+                chip.tick();
+                audio.update(chip.is_playing_sound(), chip.get_audio_pattern());
+                self.ticks += 1;
+
+                let stolen_cycles = self.frame_overhead_cycles();
+                audio.advance(stolen_cycles as f64 / cycle_frequency);
+                self.cycles += stolen_cycles;
+                self.pending_cycles -= stolen_cycles as f64;
+            }
+
+            self.apply_key_events(chip);
+
+            let cost = chip.step()?;
+            let cycles = match self.timing {
+                TimingMode::Fixed => 1,
+                TimingMode::Vip if cost.wait_for_vblank => {
+                    let next_tick = self.tick_cycle(self.ticks_due() + 1);
+                    (cost.machine_cycles as u64).max(next_tick - self.cycles)
+                }
+                TimingMode::Vip => cost.machine_cycles as u64,
+            };
+            audio.update(chip.is_playing_sound(), chip.get_audio_pattern());
+            audio.advance(cycles as f64 / cycle_frequency);
+            self.cycles += cycles;
+            self.pending_cycles -= cycles as f64;
+        }
+        Ok(())
+    }
+
+    fn apply_key_events(&mut self, chip: &mut Chip8) {
+        while let Some(event) = self.key_events.front() {
+            if event.cycle > self.cycles {
+                break;
+            }
+            // The core only sees the first press and the last release of the sources
+            let held = &mut self.held_keys[event.key];
+            let was_held = *held != 0;
+            if event.pressed {
+                *held |= 1 << event.source as u8;
+            } else {
+                *held &= !(1 << event.source as u8);
+            }
+            match (was_held, *held != 0) {
+                (false, true) => chip.key_down(event.key),
+                (true, false) => chip.key_up(event.key),
+                _ => {}
+            }
+            self.key_events.pop_front();
+        }
+    }
+
+    // Cycles of each frame during which no instruction runs
+    fn frame_overhead_cycles(&self) -> u64 {
+        match self.timing {
+            TimingMode::Fixed => 0,
+            TimingMode::Vip => VIP_DISPLAY_DMA_CYCLES + VIP_INTERRUPT_CYCLES,
+        }
+    }
+
+    fn cycle_frequency(&self) -> f64 {
+        match self.timing {
+            TimingMode::Fixed => self.cpu_frequency,
+            TimingMode::Vip => VIP_MACHINE_CYCLE_FREQUENCY,
+        }
+    }
+
+    // Ticks whose emulated time has been reached by the elapsed cycles
+    fn ticks_due(&self) -> u64 {
+        (self.cycles as f64 * TIMER_FREQUENCY / self.cycle_frequency()).floor() as u64
+    }
+
+    // First cycle at which `tick` is due
+    fn tick_cycle(&self, tick: u64) -> u64 {
+        (tick as f64 * self.cycle_frequency() / TIMER_FREQUENCY).ceil() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audio::NullSink;
+    use crate::chip8::CHIP_FREQUENCY;
+
+    // Jumps to itself
+    fn idle_chip() -> Chip8 {
+        Chip8::new(vec![0x12, 0x00]).unwrap()
+    }
+
+    #[test]
+    fn ticks_do_not_drift() {
+        // 11.67 instructions per tick
+        let mut scheduler = Scheduler::new(700.0, TimingMode::Fixed);
+        let mut chip = idle_chip();
+        for _ in 0..600 {
+            scheduler.run(&mut chip, 1.0 / TIMER_FREQUENCY, &mut NullSink).unwrap();
+        }
+        assert_eq!(scheduler.cycles, 7000);
+        // The tick at exactly 10 seconds comes before the next instruction
+        assert_eq!(scheduler.ticks, 599);
+        scheduler.run(&mut chip, 1.0 / 700.0, &mut NullSink).unwrap();
+        assert_eq!(scheduler.ticks, 600);
+    }
+
+    #[test]
+    fn ticks_come_between_the_instructions_around_them() {
+        // The first tick is at 11.67 cycles, before the 13th instruction
+        let mut scheduler = Scheduler::new(700.0, TimingMode::Fixed);
+        let mut chip = idle_chip();
+        scheduler.run(&mut chip, 12.0 / 700.0, &mut NullSink).unwrap();
+        assert_eq!((scheduler.cycles, scheduler.ticks), (12, 0));
+        scheduler.run(&mut chip, 1.0 / 700.0, &mut NullSink).unwrap();
+        assert_eq!((scheduler.cycles, scheduler.ticks), (13, 1));
+        assert_eq!(scheduler.tick_cycle(2), 24);
+    }
+
+    #[test]
+    fn fractions_of_cycles_are_carried_over() {
+        let mut scheduler = Scheduler::new(700.0, TimingMode::Fixed);
+        let mut chip = idle_chip();
+        for _ in 0..10_000 {
+            scheduler.run(&mut chip, 0.001, &mut NullSink).unwrap();
+        }
+        assert_eq!(scheduler.cycles, 7000);
+    }
+
+    #[test]
+    fn paused_frames_do_not_run() {
+        let mut scheduler = Scheduler::new(600.0, TimingMode::Fixed);
+        let mut chip = idle_chip();
+        scheduler.toggle_pause();
+        scheduler.run_frame(&mut chip, 1.0, &mut NullSink).unwrap();
+        assert_eq!((scheduler.cycles, scheduler.ticks), (0, 0));
+
+        // Frame advance, one frame of emulated time while paused
+        scheduler.run(&mut chip, 1.0 / TIMER_FREQUENCY, &mut NullSink).unwrap();
+        scheduler.run(&mut chip, 1.0 / TIMER_FREQUENCY, &mut NullSink).unwrap();
+        assert_eq!((scheduler.cycles, scheduler.ticks), (20, 1));
+        assert!(scheduler.is_paused());
+
+        scheduler.toggle_pause();
+        scheduler.run_frame(&mut chip, 1.0 / TIMER_FREQUENCY, &mut NullSink).unwrap();
+        assert_eq!(scheduler.cycles, 30);
+    }
+
+    #[test]
+    fn speed_scales_the_emulated_time() {
+        let mut scheduler = Scheduler::new(600.0, TimingMode::Fixed);
+        let mut chip = idle_chip();
+        scheduler.set_speed(Speed::Multiplier(0.5));
+        scheduler.run_frame(&mut chip, 0.1, &mut NullSink).unwrap();
+        assert_eq!(scheduler.cycles, 30);
+        scheduler.change_speed(2);
+        assert_eq!(scheduler.speed(), Speed::Multiplier(2.0));
+        scheduler.run_frame(&mut chip, 0.1, &mut NullSink).unwrap();
+        assert_eq!(scheduler.cycles, 150);
+        scheduler.change_speed(10);
+        assert_eq!(scheduler.speed(), Speed::Unlimited);
+    }
+
+    // VIP machine cycles of the first instruction of `rom`
+    fn vip_cost(rom: &[u8]) -> u64 {
+        Chip8::new(rom.to_vec()).unwrap().step().unwrap().machine_cycles as u64
+    }
+
+    #[test]
+    fn vip_sprites_wait_for_the_vertical_blank() {
+        // Draws a row at 0, 0, then jumps to itself
+        let rom = [0xD0, 0x01, 0x12, 0x02];
+        let mut scheduler = Scheduler::new(CHIP_FREQUENCY, TimingMode::Vip);
+        let mut chip = Chip8::new(rom.to_vec()).unwrap();
+        let first_tick = scheduler.tick_cycle(1);
+        assert!(vip_cost(&rom) < first_tick);
+        scheduler.run(&mut chip, first_tick as f64 / VIP_MACHINE_CYCLE_FREQUENCY, &mut NullSink).unwrap();
+        assert_eq!((scheduler.cycles, scheduler.ticks), (first_tick, 0));
+
+        // The tick takes the display DMA and the interrupt before the jump
+        scheduler.run(&mut chip, 1.0 / VIP_MACHINE_CYCLE_FREQUENCY, &mut NullSink).unwrap();
+        let overhead = VIP_DISPLAY_DMA_CYCLES + VIP_INTERRUPT_CYCLES;
+        assert_eq!(scheduler.ticks, 1);
+        assert_eq!(scheduler.cycles, first_tick + overhead + vip_cost(&[0x12, 0x02]));
+    }
+
+    #[test]
+    fn vip_frames_lose_the_display_dma_and_interrupt_cycles() {
+        let jump = vip_cost(&[0x12, 0x00]);
+        let overhead = VIP_DISPLAY_DMA_CYCLES + VIP_INTERRUPT_CYCLES;
+        let mut scheduler = Scheduler::new(CHIP_FREQUENCY, TimingMode::Vip);
+        let mut chip = idle_chip();
+        scheduler.run(&mut chip, 1.0, &mut NullSink).unwrap();
+        assert_eq!(scheduler.ticks, 59);
+        // Every other cycle went to the jumps
+        let instruction_cycles = scheduler.cycles - scheduler.ticks * overhead;
+        assert_eq!(instruction_cycles % jump, 0);
+        let second = VIP_MACHINE_CYCLE_FREQUENCY as u64;
+        assert!((second..second + jump).contains(&scheduler.cycles));
+    }
+
+    #[test]
+    fn key_events_apply_at_their_cycle() {
+        let mut scheduler = Scheduler::new(600.0, TimingMode::Fixed);
+        let mut chip = idle_chip();
+        scheduler.queue_key(KeySource::Keyboard, 3, true, 0.5);
+        scheduler.queue_key(KeySource::Keyboard, 3, false, 0.75);
+        scheduler.run(&mut chip, 0.5, &mut NullSink).unwrap();
+        assert!(!chip.is_key_pressed(3));
+        scheduler.run(&mut chip, 1.0 / 600.0, &mut NullSink).unwrap();
+        assert!(chip.is_key_pressed(3));
+        scheduler.run(&mut chip, 0.25, &mut NullSink).unwrap();
+        assert!(!chip.is_key_pressed(3));
+    }
+
+    #[test]
+    fn key_events_of_a_cycle_apply_in_order() {
+        let mut scheduler = Scheduler::new(600.0, TimingMode::Fixed);
+        let mut chip = idle_chip();
+        scheduler.queue_key(KeySource::Keyboard, 1, true, 0.1);
+        scheduler.queue_key(KeySource::Keyboard, 2, true, 0.0);
+        scheduler.queue_key(KeySource::Keyboard, 1, false, 0.1);
+        scheduler.queue_key(KeySource::Keyboard, 2, false, 0.1);
+        scheduler.queue_key(KeySource::Keyboard, 2, true, 0.1);
+        scheduler.run(&mut chip, 0.2, &mut NullSink).unwrap();
+        assert!(!chip.is_key_pressed(1));
+        assert!(chip.is_key_pressed(2));
+    }
+
+    #[test]
+    fn keys_are_held_until_every_source_releases_them() {
+        let mut scheduler = Scheduler::new(600.0, TimingMode::Fixed);
+        let mut chip = idle_chip();
+        let step = 1.0 / 600.0;
+        scheduler.key_down(KeySource::Keyboard, 4);
+        scheduler.key_down(KeySource::Gamepad, 4);
+        scheduler.key_up(KeySource::Keyboard, 4);
+        scheduler.run(&mut chip, step, &mut NullSink).unwrap();
+        assert!(chip.is_key_pressed(4));
+        // A source releasing a key it does not hold changes nothing
+        scheduler.key_up(KeySource::Panel, 4);
+        scheduler.run(&mut chip, step, &mut NullSink).unwrap();
+        assert!(chip.is_key_pressed(4));
+        scheduler.key_up(KeySource::Gamepad, 4);
+        scheduler.run(&mut chip, step, &mut NullSink).unwrap();
+        assert!(!chip.is_key_pressed(4));
+
+        scheduler.key_down(KeySource::Panel, 4);
+        scheduler.release_keys();
+        scheduler.run(&mut chip, step, &mut NullSink).unwrap();
+        assert!(!chip.is_key_pressed(4));
+    }
+
+    #[test]
+    fn keys_past_f_are_ignored() {
+        let mut scheduler = Scheduler::new(600.0, TimingMode::Fixed);
+        let mut chip = idle_chip();
+        scheduler.key_down(KeySource::Keyboard, NUMBER_OF_KEYS);
+        scheduler.key_up(KeySource::Gamepad, usize::MAX);
+        scheduler.run(&mut chip, 0.1, &mut NullSink).unwrap();
+        chip.key_down(NUMBER_OF_KEYS);
+        chip.key_up(NUMBER_OF_KEYS + 1);
+        assert!((0..NUMBER_OF_KEYS).all(|key| !chip.is_key_pressed(key)));
+    }
+}
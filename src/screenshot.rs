@@ -0,0 +1,66 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::chip8::{Chip8, DISPLAY_WIDTH, DISPLAY_HEIGHT};
+use crate::palette::Palette;
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+// Saves the current display of `chip` with `palette`, each pixel scaled to `scale` x `scale`
+pub fn capture(chip: &Chip8, palette: &Palette, scale: usize, directory: &Path) -> Result<PathBuf, Box<dyn std::error::Error>> {
+    let display = chip.get_display();
+    let colors: Vec<u32> = display.iter().map(|pixel| palette.color(*pixel)).collect();
+    let path = timestamped_path(directory, "screenshot", "png");
+    save_png(&path, &colors, DISPLAY_WIDTH, DISPLAY_HEIGHT, scale)?;
+    Ok(path)
+}
+
+pub fn save_png(path: &Path, colors: &[u32], width: usize, height: usize, scale: usize) -> Result<(), Box<dyn std::error::Error>> {
+    let scaled_width = width * scale;
+    let scaled_height = height * scale;
+    let mut data = Vec::with_capacity(scaled_width * scaled_height * 3);
+    for j in 0..scaled_height {
+        for i in 0..scaled_width {
+            data.extend_from_slice(&to_rgb(colors[i / scale + (j / scale) * width]));
+        }
+    }
+
+    let writer = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(writer, scaled_width as u32, scaled_height as u32);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(&data)?;
+    Ok(())
+}
+
+pub fn to_rgb(color: u32) -> [u8; 3] {
+    [(color >> 16) as u8, (color >> 8) as u8, color as u8]
+}
+
+// e.g. chip8-screenshot-20200412-153000-042.png, in UTC
+pub fn timestamped_path(directory: &Path, kind: &str, extension: &str) -> PathBuf {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let seconds = now.as_secs();
+    let (year, month, day) = civil_from_days((seconds / SECONDS_PER_DAY) as i64);
+    let time_of_day = seconds % SECONDS_PER_DAY;
+    directory.join(format!("chip8-{}-{:04}{:02}{:02}-{:02}{:02}{:02}-{:03}.{}",
+                           kind, year, month, day,
+                           time_of_day / 3600, (time_of_day / 60) % 60, time_of_day % 60,
+                           now.subsec_millis(), extension))
+}
+
+// Days since 1970-01-01 to (year, month, day), from http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = if z >= 0 { z } else { z - 146_096 } / 146_097;
+    let day_of_era = z - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
@@ -0,0 +1,45 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/*
+ * Watches a file by polling its modification time.
+ * Checked at most every POLL_INTERVAL, so it can be called every frame.
+ */
+pub struct FileWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    last_poll: Instant,
+}
+
+impl FileWatcher {
+    pub fn new(path: &Path) -> Self {
+        FileWatcher {
+            path: path.to_path_buf(),
+            modified: modified(path),
+            last_poll: Instant::now(),
+        }
+    }
+
+    // True once after each change, a missing file is not a change, e.g. while it is rebuilt
+    pub fn changed(&mut self) -> bool {
+        if self.last_poll.elapsed() < POLL_INTERVAL {
+            return false;
+        }
+        self.last_poll = Instant::now();
+
+        match modified(&self.path) {
+            Some(time) if Some(time) != self.modified => {
+                self.modified = Some(time);
+                true
+            }
+            _ => false
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}